@@ -13,80 +13,25 @@
 
 use std::io::Error as IOError;
 use std::io::ErrorKind as IOErrorKind;
-use std::io::{Seek, SeekFrom, Read, Write};
+use std::io::{Seek, SeekFrom, Read, Write, BufReader};
+use std::cmp::Ordering;
 use std::fs::{File, OpenOptions};
 use std::path::Path;
 
 use serde;
 
+use histogram::bucket_sizes;
+use offset::NodeOffset;
+pub use storage::Storage;
+
 use bincode::{
     serialize_into,
     deserialize_from,
+    serialized_size,
     Infinite,
+    Bounded,
 };
 
-/// The Storage trait provides functions needed to put and get btrees
-/// from some (possibly persistent) storage medium.
-pub trait Storage {
-    /// Put data into storage at the given offset.
-    fn put(&mut self, data: &[u8], offset: u64) -> Result<(), IOError>;
-
-    /// Append data to the storage location returning the offset
-    /// where the write began.
-    fn append(&mut self, data: &[u8]) -> Result<u64, IOError> {
-        Err(IOError::new(IOErrorKind::Other, "not implemented"))
-    }
-
-    /// Get data from storage at the given offset. Attempts to fill
-    /// the entire slice refered to by buffer, if it cannot (ie. not
-    /// enough data) then the result will be an error.
-    ///
-    /// # Arguments
-    ///
-    /// * `offset` - the "address" to begin reading at
-    ///
-    /// * `buffer` - the buffer to fill with the data. This should
-    ///              work like the buffer parameter to
-    ///              `Read::read_exact()`
-    fn get(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), IOError>;
-}
-
-impl Storage for File {
-    fn put(&mut self, data: &[u8], offset: u64) -> Result<(), IOError> {
-        self.seek(SeekFrom::Start(offset)).map_err(|err| err)
-            .and_then(|at| {
-                if at != offset {
-                    Err(IOError::new(IOErrorKind::Other, "failed to seek"))
-                }
-                else {
-                    self.write_all(data)
-                }
-            })
-    }
-
-    fn append(&mut self, data: &[u8]) -> Result<u64, IOError> {
-        self.seek(SeekFrom::End(0)).map_err(|err| err)
-            .and_then(|at| {
-                match self.write_all(data) {
-                    Ok(_)    => Ok(at),
-                    Err(err) => Err(err),
-                }
-            })
-    }
-
-    fn get(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), IOError> {
-        self.seek(SeekFrom::Start(offset)).map_err(|err| err)
-            .and_then(|at| {
-                if at != offset {
-                    Err(IOError::new(IOErrorKind::Other, "failed to seek"))
-                }
-                else {
-                    self.read_exact(buffer)
-                }
-            })
-    }
-}
-
 /// A node needs to have m data elements and m+1 children pointers.
 ///
 /// NOTE: To make this work D needs to have a fixed size when it is
@@ -113,11 +58,11 @@ impl Storage for File {
 /// written at. Instead I guess we write append-only (Could use a
 /// BufWriter if safety is not absolutely necessary) and return the
 /// offset where the node was written.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Node<K, V> {
     num_children: usize,
-    parent:       u64,
-    children:     Vec<u64>,
+    parent:       NodeOffset,
+    children:     Vec<NodeOffset>,
     data:         Vec<(K, V)>,
 }
 
@@ -129,27 +74,65 @@ impl<K, V> Node<K, V>
     /// significantly easier because we can use
     /// deserialize_/serialize_from. Rather than out own Storage trait
     /// we just require Read and Seek
-    fn load<R: Read + Seek>(from: &mut R, at: u64)
+    fn load<R: Read + Seek>(from: &mut R, at: NodeOffset)
                             -> Result<Node<K, V>, IOError> {
         // can't just do a read... need to read at the specified offset.
-        try!(from.seek(SeekFrom::Start(at)));
+        try!(from.seek(SeekFrom::Start(at.0)));
         deserialize_from(from, Infinite)
             .map_err(|_| IOError::new(IOErrorKind::Other,
                                       "failed to deserialize node"))
     }
     
     fn store<W: Write + Seek>(&self, to: &mut W)
-                                   -> Result<u64, IOError> {
+                                   -> Result<NodeOffset, IOError> {
         let offset = try!(to.seek(SeekFrom::End(0)));
         serialize_into(to, self, Infinite)
             .map_err(|_|
                      IOError::new(IOErrorKind::Other,
                                   "failed to serialize node"))
-            .map(|_| offset)
+            .map(|_| NodeOffset(offset))
+    }
+
+    /// Create an empty node for a tree of the given degree. The
+    /// vectors are sized up front for the `2 * degree + 1` entries
+    /// (and one more child) a node holds just before it is split, so
+    /// inserting into the node never reallocates.
+    fn new(degree: usize) -> Node<K,V> {
+        Node { num_children: 0,
+               children:     Vec::with_capacity(2 * degree + 2),
+               data:         Vec::with_capacity(2 * degree + 1),
+               parent:       NodeOffset(0) }
     }
 
-    fn new() -> Node<K,V> {
-        Node { num_children: 0, children: vec![], data: vec![], parent: 0 }
+    /// Grow a node read from the file (whose vectors are exactly as
+    /// long as their contents) to the same capacity `new` gives, so
+    /// the insert that follows doesn't reallocate a second time.
+    fn reserve(&mut self, degree: usize) {
+        let data = (2 * degree + 1).saturating_sub(self.data.len());
+        let children = (2 * degree + 2).saturating_sub(self.children.len());
+        self.data.reserve_exact(data);
+        self.children.reserve_exact(children);
+    }
+}
+
+impl<K, V> Node<K, V>
+    where K: Ord,
+          V: Clone {
+
+    /// A leaf is a node without children. This goes by
+    /// `num_children` alone, so it is safe on a new node whose
+    /// `children` is still empty.
+    fn is_leaf(&self) -> bool {
+        self.num_children == 0
+    }
+
+    /// Search for `key` in this node only.
+    fn search(&self, key: &K) -> SearchResult<V> {
+        match self.data.binary_search_by(|&(ref k, _)| k.cmp(key)) {
+            Ok(i)  => SearchResult::Found(self.data[i].1.clone()),
+            Err(_) if self.is_leaf() => SearchResult::NotFound,
+            Err(i) => SearchResult::SearchChild(self.children[i]),
+        }
     }
 }
 
@@ -160,53 +143,668 @@ impl<K, V> Node<K, V>
 //
 // We can write the offset to the end of the file immediately
 // following the root node. 
-struct BTree<K,V> {
+pub struct BTree<K,V> {
     storage:     File,
-    root_offset: u64,
+    root_offset: NodeOffset,
     root:        Node<K, V>,
     degree:      usize,
+    bytes_written:  u64, // Appended by inserts, for
+    bytes_inserted: u64, // `write_amplification()`
+}
+
+/// Marks a footer, so that `open()` can tell one from whatever else
+/// happens to be at the end of a truncated or foreign file. Footers
+/// from before the degree was stored used `0x4254_5246`.
+const FOOTER_MAGIC: u32 = 0x4254_5247;
+
+/// Written after every update to locate the current root.
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    magic:  u32,
+    degree: u64,
+    root:   NodeOffset,
+}
+
+impl Footer {
+    fn new(degree: usize, root: NodeOffset) -> Footer {
+        Footer { magic: FOOTER_MAGIC, degree: degree as u64, root: root }
+    }
+
+    /// The serialized size of a footer, which doesn't depend on what
+    /// it holds.
+    fn length() -> u64 {
+        serialized_size(&Footer::new(0, NodeOffset(0)))
+    }
+
+    fn store<W: Write>(&self, to: &mut W) -> Result<(), IOError> {
+        serialize_into(to, self, Infinite)
+            .map_err(|_| IOError::new(IOErrorKind::Other,
+                                      "failed to serialize tree footer"))
+    }
 }
 
 enum SearchResult<D> { 
     Found(D),
-    SearchChild(u64),
+    SearchChild(NodeOffset),
     NotFound
 }
 
 impl<K, V> BTree<K, V>
-    where K: serde::Serialize + serde::Deserialize,
-          V: serde::Serialize + serde::Deserialize {
+    where K: serde::Serialize + serde::Deserialize + Ord + Clone,
+          V: serde::Serialize + serde::Deserialize + Clone {
 
-    /// Create a new BTree
+    /// Create a new BTree. The file must not already exist. Nodes
+    /// hold at most `2 * degree` entries.
     pub fn new(name: &str, degree: usize) -> Result<BTree<K, V>, IOError> {
+        if degree == 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "degree must be at least 1"));
+        }
         let btree_path = Path::new(name);
         let mut file = try!(OpenOptions::new()
                             .write(true)
                             .read(true)
+                            .create_new(true)
                             .open(btree_path));
-        let root: Node<K, V> = Node::new();
+        let root: Node<K, V> = Node::new(degree);
         let root_offset = try!(root.store(&mut file));
         // Store the location of the root node at the end of the file.
         // This isn't really necessary for an empty tree, but once the
         // root moves, and changes size we will need the "footer"
         // locate the root node.
-        serialize_into(&mut file, &root_offset, Infinite)
-            .map_err(|err| IOError::new(IOErrorKind::Other,
-                                        "failed to serialize tree footer"))
+        Footer::new(degree, root_offset).store(&mut file)
             .map(|_|
                  BTree { storage: file,
                          root_offset: root_offset,
                          root: root,
-                         degree: degree }
+                         degree: degree,
+                         bytes_written: 0,
+                         bytes_inserted: 0 }
             )
     }
 
+    /// Open an existing BTree. The footer at the end of the file
+    /// locates the root and records the degree the tree was created
+    /// with. Fails with `InvalidData` if the file doesn't end in a
+    /// footer, as when it was truncated or written by an older
+    /// version whose footer didn't record the degree.
+    pub fn open(name: &str) -> Result<BTree<K, V>, IOError> {
+        let btree_path = Path::new(name);
+        let mut file = try!(OpenOptions::new()
+                            .write(true)
+                            .read(true)
+                            .open(btree_path));
+        let file_length = try!(file.metadata()).len();
+        let footer_length = Footer::length();
+        if file_length < footer_length {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "file is too short to hold a footer"));
+        }
+        try!(file.seek(SeekFrom::End(-(footer_length as i64))));
+        let footer: Footer = try!(deserialize_from(&mut file, Infinite)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                      "failed to deserialize tree footer")));
+        // A root has to be written before the footer that points at
+        // it.
+        if footer.magic != FOOTER_MAGIC
+            || footer.degree == 0
+            || footer.degree > usize::max_value() as u64
+            || footer.root.0 >= file_length - footer_length {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "malformed tree footer"));
+        }
+        // Bound the read by the file, as `set_root()` does, so a
+        // footer pointing at garbage can't claim a huge vector.
+        let root_offset = footer.root;
+        try!(file.seek(SeekFrom::Start(root_offset.0)));
+        let limit = Bounded(file_length - footer_length - root_offset.0);
+        let root: Node<K, V> = try!(deserialize_from(&mut file, limit)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                      "failed to deserialize node")));
+        Ok(BTree { storage: file,
+                   root_offset: root_offset,
+                   root: root,
+                   degree: footer.degree as usize,
+                   bytes_written: 0,
+                   bytes_inserted: 0 })
+    }
+
     pub fn get(&mut self, key: K) -> Result<Option<V>, IOError> {
-        let mut search_done = false;
-        while !search_done {
-            let mut ref node = self.root;
-            match
+        let mut result = self.root.search(&key);
+        loop {
+            match result {
+                SearchResult::Found(value)        => return Ok(Some(value)),
+                SearchResult::NotFound            => return Ok(None),
+                SearchResult::SearchChild(offset) => {
+                    let node: Node<K, V> =
+                        try!(Node::load(&mut self.storage, offset));
+                    result = node.search(&key);
+                },
+            }
+        }
+    }
+
+    /// Look up `key` as `get()` does, but without loading whole
+    /// nodes. Below the root, entries are decoded one at a time
+    /// straight from the file, and the search of each node stops as
+    /// soon as it reaches `key` or passes it, so the rest of the node
+    /// is never decoded.
+    ///
+    /// This pays off for nodes with many entries or large ones. The
+    /// values ahead of the key are still decoded, since their length
+    /// isn't known until they are, and the file is read through a
+    /// buffer, so a small node is read whole anyway. Nothing read is
+    /// kept for the next lookup, whereas a loaded node could be
+    /// cached and searched again for free.
+    pub fn get_streaming(&mut self, key: K) -> Result<Option<V>, IOError> {
+        let mut result = self.root.search(&key);
+        loop {
+            match result {
+                SearchResult::Found(value)        => return Ok(Some(value)),
+                SearchResult::NotFound            => return Ok(None),
+                SearchResult::SearchChild(offset) =>
+                    result = try!(self.search_stored(offset, &key)),
+            }
+        }
+    }
+
+    /// `Node::search()` for the node at `at`, decoding fields in the
+    /// order they are serialized and no further than needed.
+    fn search_stored(&mut self, at: NodeOffset, key: &K)
+                     -> Result<SearchResult<V>, IOError> {
+        fn decode<R: Read, T: serde::Deserialize>(from: &mut R)
+                                                   -> Result<T, IOError> {
+            deserialize_from(from, Infinite)
+                .map_err(|_| IOError::new(IOErrorKind::Other,
+                                          "failed to deserialize node"))
+        }
+        try!(self.storage.seek(SeekFrom::Start(at.0)));
+        let mut reader = BufReader::new(&mut self.storage);
+        let num_children: usize = try!(decode(&mut reader));
+        let _parent: NodeOffset = try!(decode(&mut reader));
+        let children: Vec<NodeOffset> = try!(decode(&mut reader));
+        let num_entries: u64 = try!(decode(&mut reader));
+        let mut i = 0;
+        while i < num_entries as usize {
+            let k: K = try!(decode(&mut reader));
+            let v: V = try!(decode(&mut reader));
+            match k.cmp(key) {
+                Ordering::Less    => i += 1,
+                Ordering::Equal   => return Ok(SearchResult::Found(v)),
+                Ordering::Greater => break,
+            }
+        }
+        if num_children == 0 {
+            return Ok(SearchResult::NotFound);
+        }
+        match children.get(i) {
+            Some(child) => Ok(SearchResult::SearchChild(*child)),
+            None        => Err(IOError::new(IOErrorKind::InvalidData,
+                                            "node has too few children")),
+        }
+    }
+
+    /// Insert a key and value. Nothing is modified in place: the leaf
+    /// and every node on the path back up to the root are appended to
+    /// the file as new copies, followed by a footer pointing at the
+    /// new root.
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), IOError> {
+        let entry_length = serialized_size(&(&key, &value));
+        let mut path: Vec<(Node<K, V>, usize)> = vec![];
+        let mut node = self.root.clone();
+        loop {
+            node.reserve(self.degree);
+            match node.data.binary_search_by(|&(ref k, _)| k.cmp(&key)) {
+                Ok(_) => return Err(IOError::new(IOErrorKind::AlreadyExists,
+                                                 "key already exists")),
+                Err(i) if node.is_leaf() => {
+                    node.data.insert(i, (key, value));
+                    break;
+                },
+                Err(i) => {
+                    let child = try!(Node::load(&mut self.storage,
+                                                node.children[i]));
+                    path.push((node, i));
+                    node = child;
+                },
+            }
+        }
+
+        let start = try!(self.storage.seek(SeekFrom::End(0)));
+        let (mut offset, mut node, mut split) = try!(self.store_split(node));
+        while let Some((mut parent, i)) = path.pop() {
+            parent.children[i] = offset;
+            if let Some((median, right_offset)) = split {
+                parent.data.insert(i, median);
+                parent.children.insert(i + 1, right_offset);
+                parent.num_children = parent.children.len();
+            }
+            let stored = try!(self.store_split(parent));
+            offset = stored.0;
+            node = stored.1;
+            split = stored.2;
+        }
+        if let Some((median, right_offset)) = split {
+            // The root split, so the tree grows by one level.
+            let mut root = Node::new(self.degree);
+            root.data.push(median);
+            root.children.push(offset);
+            root.children.push(right_offset);
+            root.num_children = 2;
+            offset = try!(root.store(&mut self.storage));
+            node = root;
+        }
+        try!(Footer::new(self.degree, offset).store(&mut self.storage));
+        let end = try!(self.storage.seek(SeekFrom::Current(0)));
+        self.bytes_written += end - start;
+        self.bytes_inserted += entry_length;
+        self.root_offset = offset;
+        self.root = node;
+        Ok(())
+    }
+
+    /// The offset the next node written will land at. Nodes are
+    /// appended after the footer, which stays where it is and is
+    /// simply superseded by the footer that follows the new nodes, so
+    /// this is the current length of the file.
+    pub fn tail_offset(&mut self) -> Result<u64, IOError> {
+        self.storage.seek(SeekFrom::End(0))
+    }
+
+    /// Bytes appended to the file for every byte of serialized keys
+    /// and values inserted, since the tree was opened or
+    /// `reset_write_amplification()` was last called. Each insert
+    /// appends a copy of every node on the path to the root plus a
+    /// footer, so this grows with the height of the tree and the
+    /// size of its nodes. Returns 0 if nothing has been inserted.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_inserted == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.bytes_inserted as f64
+    }
+
+    /// Start a new window for `write_amplification()`.
+    pub fn reset_write_amplification(&mut self) {
+        self.bytes_written = 0;
+        self.bytes_inserted = 0;
+    }
+
+    /// Count the nodes reachable from the root by encoded size, in
+    /// `buckets` equal-width buckets spanning the smallest to the
+    /// largest size seen. Returns the lowest size in each bucket and
+    /// the number of nodes that fall into it, smallest first.
+    ///
+    /// Nodes take up only as many bytes as they encode to, and a read
+    /// of a node reads all of them, so large keys or values show up
+    /// here as nodes that are expensive to read. Copies of nodes that
+    /// later inserts superseded aren't counted. It reads every node in
+    /// the tree.
+    pub fn node_size_histogram(&mut self, buckets: usize)
+                               -> Result<Vec<(u64, u64)>, IOError> {
+        let mut sizes = vec![];
+        try!(self.for_each_node(|node| sizes.push(serialized_size(node))));
+        bucket_sizes(sizes, buckets)
+    }
+
+    /// Call `f` with every node reachable from the root, parents
+    /// before their children.
+    fn for_each_node<F>(&mut self, mut f: F) -> Result<(), IOError>
+        where F: FnMut(&Node<K, V>) {
+        f(&self.root);
+        let mut pending: Vec<NodeOffset> =
+            self.root.children.iter().rev().cloned().collect();
+        while let Some(offset) = pending.pop() {
+            let node: Node<K, V> = try!(Node::load(&mut self.storage, offset));
+            f(&node);
+            pending.extend(node.children.iter().rev().cloned());
+        }
+        Ok(())
+    }
+
+    /// Make the node at `offset` the root of the tree, for tools that
+    /// write nodes to the file themselves (after a custom compaction,
+    /// say). A footer pointing at the node is appended, so the change
+    /// survives reopening the tree.
+    ///
+    /// A wrong offset loses every entry not reachable from the new
+    /// root, so the node is checked before anything is written. It
+    /// must lie before the current footer and decode as a node, hold
+    /// at most `2 * degree` entries with keys in ascending order, and
+    /// be either a leaf or have one more child than it has entries.
+    /// Its children must come before it in the file, as every node
+    /// written by the tree's own appends does. Only the node itself
+    /// is checked, not the subtree below it, and nothing marks where
+    /// nodes begin, so an offset into the middle of a node may still
+    /// pass.
+    pub fn set_root(&mut self, offset: NodeOffset) -> Result<(), IOError> {
+        let footer_length = Footer::length();
+        let file_length = try!(self.storage.seek(SeekFrom::End(0)));
+        // An open tree always ends in a footer, so this can't
+        // underflow, and unlike adding to `offset` it can't overflow.
+        if offset.0 > file_length - footer_length {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "root offset is past the end of the tree"));
+        }
+        // Bound the read by the file so that garbage at `offset` can't
+        // claim a huge vector.
+        try!(self.storage.seek(SeekFrom::Start(offset.0)));
+        let limit = Bounded(file_length - footer_length - offset.0);
+        let root: Node<K, V> = try!(deserialize_from(&mut self.storage, limit)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                      "failed to deserialize node")));
+        let plausible =
+            root.data.len() <= 2 * self.degree
+            && root.children.len() == root.num_children
+            && (root.num_children == 0
+                || root.num_children == root.data.len() + 1)
+            && root.data.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            && root.children.iter().all(|child| *child < offset);
+        if !plausible {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "node is not a plausible root"));
+        }
+        try!(self.storage.seek(SeekFrom::End(0)));
+        try!(Footer::new(self.degree, offset).store(&mut self.storage));
+        self.root_offset = offset;
+        self.root = root;
+        Ok(())
+    }
+
+    /// Append `node` to the file, first splitting it in two if it has
+    /// more than `2 * degree` entries. Returns the offset of the node
+    /// (or of its left half) and the node itself, along with the
+    /// median entry and offset of the right half if it was split.
+    fn store_split(&mut self, mut node: Node<K, V>)
+                   -> Result<(NodeOffset, Node<K, V>, Option<((K, V), NodeOffset)>),
+                             IOError> {
+        if node.data.len() <= 2 * self.degree {
+            let offset = try!(node.store(&mut self.storage));
+            return Ok((offset, node, None));
+        }
+        let mut right = Node::new(self.degree);
+        right.data.extend(node.data.drain(self.degree + 1..));
+        let median = node.data.pop().unwrap();
+        if !node.is_leaf() {
+            right.children.extend(node.children.drain(self.degree + 1..));
+            right.num_children = right.children.len();
+            node.num_children = node.children.len();
+        }
+        let offset = try!(node.store(&mut self.storage));
+        let right_offset = try!(right.store(&mut self.storage));
+        Ok((offset, node, Some((median, right_offset))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BTree, Footer, Node};
+    use bincode::serialized_size;
+    use offset::NodeOffset;
+    use std::fs::{self, OpenOptions};
+    use std::io::{ErrorKind, Write};
+    use std::u64;
+
+    #[test]
+    fn reserved_nodes_fill_without_reallocating() {
+        let mut node: Node<u64, u64> = Node::new(0);
+        node.data.push((1, 1));
+        node.children.push(NodeOffset(0));
+        node.reserve(3);
+        let data = node.data.as_ptr();
+        let children = node.children.as_ptr();
+        for i in 2..8 {
+            node.data.push((i, i));
+            node.children.push(NodeOffset(0));
+        }
+        node.children.push(NodeOffset(0));
+        assert_eq!(data, node.data.as_ptr());
+        assert_eq!(children, node.children.as_ptr());
+    }
+
+    #[test]
+    fn node_size_histogram_counts_live_nodes() {
+        let name = "node_size_histogram_counts_live_nodes.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree: BTree<u64, String> = BTree::new(name, 2).unwrap();
+            let size = serialized_size(&tree.root);
+            assert_eq!(tree.node_size_histogram(2).unwrap(),
+                       vec![(size, 1), (size + 1, 0)]);
+            for key in 0..40 {
+                // Every tenth value is large, and bloats its node.
+                let length = if key % 10 == 0 { 1000 } else { 1 };
+                tree.insert(key, "x".repeat(length)).unwrap();
+            }
+            assert!(tree.node_size_histogram(0).is_err());
+            let histogram = tree.node_size_histogram(4).unwrap();
+            assert_eq!(histogram.len(), 4);
+            assert!(histogram.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            let mut num_nodes = 0;
+            tree.for_each_node(|_| num_nodes += 1).unwrap();
+            assert_eq!(histogram.iter().map(|&(_, n)| n).sum::<u64>(),
+                       num_nodes);
+            // The nodes holding a large value land in the top buckets,
+            // the rest in the bottom one.
+            assert!(histogram[0].1 > 0 && histogram[3].1 > 0);
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn get_streaming_agrees_with_get() {
+        let name = "get_streaming_agrees_with_get.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree: BTree<i64, String> = BTree::new(name, 2).unwrap();
+            for i in 0..200 {
+                let key = (i * 73) % 200 * 2;
+                tree.insert(key, "x".repeat(key as usize % 7)).unwrap();
+            }
+            let mut num_nodes = 0;
+            tree.for_each_node(|_| num_nodes += 1).unwrap();
+            assert!(num_nodes > 1);
+        }
+        let mut tree: BTree<i64, String> = BTree::open(name).unwrap();
+        // Odd keys and the ends are absent, even keys present.
+        for key in -2..402 {
+            let expected = tree.get(key).unwrap();
+            assert_eq!(expected.is_some(), key >= 0 && key < 400
+                                           && key % 2 == 0);
+            assert_eq!(tree.get_streaming(key).unwrap(), expected);
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn write_amplification_counts_appended_bytes() {
+        let name = "write_amplification_counts_appended_bytes.btree";
+        let _ = fs::remove_file(name);
+        let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+        assert_eq!(tree.write_amplification(), 0.0);
+        for key in 0..100 {
+            tree.insert(key, 0).unwrap();
+        }
+        assert!(tree.write_amplification() > 1.0);
+        tree.reset_write_amplification();
+        assert_eq!(tree.write_amplification(), 0.0);
+        let length = fs::metadata(name).unwrap().len();
+        tree.insert(100, 0).unwrap();
+        let appended = fs::metadata(name).unwrap().len() - length;
+        // A key and a value encode to 16 bytes.
+        assert_eq!(tree.write_amplification(), appended as f64 / 16.0);
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn inserts_start_writing_at_the_tail_offset() {
+        let name = "inserts_start_writing_at_the_tail_offset.btree";
+        let _ = fs::remove_file(name);
+        let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+        // While the root is the only node it is the node written.
+        let tail = tree.tail_offset().unwrap();
+        tree.insert(1000, 0).unwrap();
+        assert_eq!(tree.root_offset, NodeOffset(tail));
+        // Going down, every key lands first in the leftmost leaf, and
+        // stays in the left half when it splits, which is written
+        // first.
+        for key in (0..200).rev() {
+            let tail = tree.tail_offset().unwrap();
+            assert_eq!(tail, fs::metadata(name).unwrap().len());
+            tree.insert(key, 0).unwrap();
+            let leaf: Node<i64, u64> =
+                Node::load(&mut tree.storage, NodeOffset(tail)).unwrap();
+            assert!(leaf.is_leaf());
+            assert_eq!(leaf.data[0], (key, 0));
+        }
+        assert!(!tree.root.is_leaf());
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn set_root_reseats_the_tree_for_good() {
+        let name = "set_root_reseats_the_tree_for_good.btree";
+        let _ = fs::remove_file(name);
+        let old_root = {
+            let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+            for key in 0..20 {
+                tree.insert(key, 1).unwrap();
+            }
+            let old_root = tree.root_offset;
+            for key in 20..40 {
+                tree.insert(key, 2).unwrap();
+            }
+            // An earlier root is a whole tree as it was back then.
+            tree.set_root(old_root).unwrap();
+            assert_eq!(tree.get(5).unwrap(), Some(1));
+            assert_eq!(tree.get(25).unwrap(), None);
+            tree.insert(40, 3).unwrap();
+            old_root
+        };
+        let mut tree: BTree<i64, u64> = BTree::open(name).unwrap();
+        assert!(tree.root_offset != old_root);
+        for key in 0..41 {
+            let expected = if key < 20 { Some(1) }
+                           else if key == 40 { Some(3) }
+                           else { None };
+            assert_eq!(tree.get(key).unwrap(), expected);
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn set_root_rejects_implausible_nodes() {
+        let name = "set_root_rejects_implausible_nodes.btree";
+        let _ = fs::remove_file(name);
+        let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+        for key in 0..20 {
+            tree.insert(key, 0).unwrap();
+        }
+        let root_offset = tree.root_offset;
+        // Keys out of order.
+        let mut unordered: Node<i64, u64> = Node::new(2);
+        unordered.data.extend(vec![(5, 0), (1, 0)]);
+        // A child that comes after the node itself.
+        let mut later_child: Node<i64, u64> = Node::new(2);
+        later_child.data.push((100, 0));
+        let length = fs::metadata(name).unwrap().len();
+        later_child.children.extend(vec![root_offset,
+                                         NodeOffset(length + 1000)]);
+        later_child.num_children = 2;
+        for node in vec![unordered, later_child] {
+            // Followed by a footer, so the node lies inside the tree.
+            let offset = node.store(&mut tree.storage).unwrap();
+            Footer::new(2, root_offset).store(&mut tree.storage).unwrap();
+            let err = tree.set_root(offset).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(tree.root_offset, root_offset);
+        }
+        drop(tree);
+        let mut tree: BTree<i64, u64> = BTree::open(name).unwrap();
+        assert_eq!(tree.root_offset, root_offset);
+        assert_eq!(tree.get(19).unwrap(), Some(0));
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn set_root_rejects_offsets_past_the_end() {
+        let name = "set_root_rejects_offsets_past_the_end.btree";
+        let _ = fs::remove_file(name);
+        let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+        tree.insert(1, 10).unwrap();
+        let length = fs::metadata(name).unwrap().len();
+        for offset in vec![length, u64::MAX - 3, u64::MAX] {
+            let err = tree.set_root(NodeOffset(offset)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(tree.get(1).unwrap(), Some(10));
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_a_truncated_footer() {
+        let name = "open_rejects_a_truncated_footer.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree: BTree<i64, u64> = BTree::new(name, 2).unwrap();
+            for key in 0..10 {
+                tree.insert(key, 0).unwrap();
+            }
+        }
+        let length = fs::metadata(name).unwrap().len();
+        for cut in 1..Footer::length() + 1 {
+            OpenOptions::new().write(true).open(name).unwrap()
+                .set_len(length - cut).unwrap();
+            match BTree::<i64, u64>::open(name) {
+                Err(ref err) if err.kind() == ErrorKind::InvalidData => (),
+                Err(err) => panic!("wrong error for a cut of {}: {}", cut, err),
+                Ok(_)    => panic!("opened with {} bytes cut", cut),
+            }
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn open_bounds_the_root_by_the_file() {
+        let name = "open_bounds_the_root_by_the_file.btree";
+        let _ = fs::remove_file(name);
+        BTree::<i64, u64>::new(name, 2).unwrap();
+        // A node whose `children` claims far more entries than the
+        // file holds, and a well-formed footer pointing at it.
+        let mut file = OpenOptions::new().append(true).open(name).unwrap();
+        let garbage = fs::metadata(name).unwrap().len();
+        file.write_all(&[0; 16]).unwrap();
+        file.write_all(&[0xff; 8]).unwrap();
+        Footer::new(2, NodeOffset(garbage)).store(&mut file).unwrap();
+        match BTree::<i64, u64>::open(name) {
+            Err(ref err) if err.kind() == ErrorKind::InvalidData => (),
+            Err(err) => panic!("wrong error: {}", err),
+            Ok(_)    => panic!("opened a garbage root"),
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn open_uses_the_stored_degree() {
+        let name = "open_uses_the_stored_degree.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree: BTree<i64, u64> = BTree::new(name, 3).unwrap();
+            for key in 0..50 {
+                tree.insert(key, key as u64).unwrap();
+            }
+        }
+        let mut tree: BTree<i64, u64> = BTree::open(name).unwrap();
+        assert_eq!(tree.degree, 3);
+        for key in 50..100 {
+            tree.insert(key, key as u64).unwrap();
+        }
+        drop(tree);
+        let mut tree: BTree<i64, u64> = BTree::open(name).unwrap();
+        assert_eq!(tree.degree, 3);
+        for key in 0..100 {
+            assert_eq!(tree.get(key).unwrap(), Some(key as u64));
         }
-        Ok(None)
+        fs::remove_file(name).unwrap();
     }
 }
@@ -1,33 +1,83 @@
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::io::Error as IOError;
 use std::io::ErrorKind as IOErrorKind;
 use std::io::{Seek, SeekFrom, Write, Read, BufReader};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::cmp;
+use std::mem;
+use std::cmp::Ordering;
+use std::collections::{HashSet, BTreeMap};
+use std::time::Instant;
+
+use histogram::bucket_sizes;
+use offset::NodeOffset;
+use storage::{Storage, MemoryStorage};
 
 use bincode::{serialize, deserialize,
               deserialize_from, serialized_size,
-              SizeLimit, Infinite};
+              Infinite, Bounded};
+use serde::Deserialize;
+
+pub type BTreeData = Option<(i64,u64)>;
+
+/// An entry as a node stores it: the key, the value, and the time the
+/// entry expires, if it does, in milliseconds since the Unix epoch.
+type Entry = (i64, u64, Option<i64>);
+
+/// The current time in milliseconds since the Unix epoch, the unit of
+/// audit log timestamps and entry expiry times.
+fn unix_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64
+}
 
-type BTreeData = Option<(i64,u64)>;
+/// The order keys are kept in. Everything that orders keys goes
+/// through here so that the tree agrees with itself (and with
+/// `BTree::compare_keys()`).
+fn compare_keys(a: &i64, b: &i64) -> Ordering {
+    a.cmp(b)
+}
 
+#[derive(Debug)]
 pub enum BTreeError {
     Exists,
     NotFound,
     IO(IOError),
 }
 
+/// Convert a `BTreeError` for functions that only report `IOError`s.
+fn io_error(err: BTreeError) -> IOError {
+    match err {
+        BTreeError::IO(ioerror) => ioerror,
+        BTreeError::Exists      =>
+            IOError::new(IOErrorKind::AlreadyExists, "key already exists"),
+        BTreeError::NotFound    =>
+            IOError::new(IOErrorKind::NotFound, "key not found"),
+    }
+}
+
 /// Representation of a node in the BTree. This derives Encodable and
 /// Decodable so we can just read and write it from the file.
-#[derive(Serialize, Deserialize)]
+///
+/// Adding a field changes the node layout, and with it the node length
+/// stored in the header, so files written with another layout fail to
+/// open rather than being misread. The header doubles as the format
+/// version: any new column has to grow `max_length()`.
+#[derive(Serialize, Deserialize, Clone)]
 struct BTreeNode {
-    children: Vec<Option<u64>>, // Offsets of this node's children
-    parent:   u64,
+    children: Vec<Option<NodeOffset>>, // Offsets of this node's children
+    counts:   Vec<u64>,                // Entries under each child
+    parent:   NodeOffset,
     data:     Vec<BTreeData>,
+    expires:  Vec<Option<i64>>,        // When each entry expires, if ever
 }
 
 impl BTreeNode {
-    fn load_node(tree: &mut BTree, offset: u64)
+    fn load_node<S: Storage>(tree: &mut BTree<S>, offset: NodeOffset)
                  -> Result<BTreeNode, BTreeError> {
         // This is actually somewhat tricky... Since I don't tnink
         // there is a simple way to determine the serialized size of a
@@ -59,36 +109,161 @@ impl BTreeNode {
         // Although judging by what is happening below this isn't
         // quite so simple...
 
-        // try to seek
-        match tree.file.seek(SeekFrom::Start(offset)) {
-            Ok(_) => {  
-              let mut encoded_node = vec![0; tree.node_length as usize];
-                match tree.file.read_exact(&mut encoded_node[..]) {
-                    Ok(_) => Ok(deserialize(&encoded_node[..])
-                                .unwrap()),
-                    Err(ioerror)     => Err(BTreeError::IO(ioerror)),
-                }
-            },
-            Err(ioerror) => Err(BTreeError::IO(ioerror))
+        let mut encoded_node = vec![0; tree.node_length as usize];
+        try!(tree.get_slot(offset, &mut encoded_node[..])
+             .map_err(BTreeError::IO));
+        let node: BTreeNode = try!(deserialize(&encoded_node[..])
+            .map_err(|_| BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidData,
+                             "failed to decode node"))));
+        // Bytes can decode without being a node of this tree. Key
+        // order isn't checked, so that `find_duplicates()` can still
+        // read a node whose keys have gone wrong.
+        if !node.has_shape(tree.k) {
+            return Err(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidData,
+                             "data at offset is not a plausible node")));
         }
-        
+        Ok(node)
     }
 
-    fn store_node(&self, tree: &mut BTree, offset: u64)
+    fn store_node<S: Storage>(&self, tree: &mut BTree<S>, offset: NodeOffset)
                   -> Result<(), IOError> {
-        let encoded_node: Vec<u8> = serialize(self, Infinite)
+        let mut encoded_node: Vec<u8> = serialize(self, Infinite)
             .unwrap(); // ?? Should we expct serialize to always succeed
-        match tree.file.seek(SeekFrom::Start(offset)) {
-            Ok(_)    => tree.file.write_all(&encoded_node[..]),
-            Err(why) => Err(why),
-        }
+        // `Option`s serialize to different sizes depending on whether
+        // they are `Some` or `None`, so pad every node out to the size
+        // of a full one. That keeps each node in a slot of exactly
+        // `node_length` bytes.
+        encoded_node.resize(tree.node_length as usize, 0);
+        tree.put_slot(offset, encoded_node)
     }
 
-    fn new(k: usize, parent: u64) -> BTreeNode {
+    fn new(k: usize, parent: NodeOffset) -> BTreeNode {
         BTreeNode {
             parent:   parent,
-            children: vec![None; 2*k],
-            data:     vec![None; 2*k + 1],
+            children: vec![None; 2*k + 1],
+            counts:   vec![0; 2*k + 1],
+            data:     vec![None; 2*k],
+            expires:  vec![None; 2*k],
+        }
+    }
+
+    /// The largest serialized size of a node in a tree of degree `k`,
+    /// which is the size of a node with every slot filled.
+    fn max_length(k: usize) -> u64 {
+        serialized_size(&BTreeNode {
+            parent:   NodeOffset(0),
+            children: vec![Some(NodeOffset(0)); 2*k + 1],
+            counts:   vec![0; 2*k + 1],
+            data:     vec![Some((0, 0)); 2*k],
+            expires:  vec![Some(0); 2*k],
+        })
+    }
+
+    /// `max_length(k)` without building a node of that size: each
+    /// step up in `k` grows a node by the same number of bytes. `None`
+    /// if `k` is 0 or the length doesn't fit in a `u64`, so it is
+    /// safe to call with a `k` read from a damaged header.
+    fn checked_length(k: usize) -> Option<u64> {
+        let smallest = BTreeNode::max_length(1);
+        let step = BTreeNode::max_length(2) - smallest;
+        (k as u64).checked_sub(1)
+            .and_then(|steps| steps.checked_mul(step))
+            .and_then(|grown| grown.checked_add(smallest))
+    }
+
+    /// Whether the node has the shape of a node in a tree of degree
+    /// `k`: vectors sized for `k`, padding only after the real
+    /// entries and children, and one more child than keys unless it
+    /// is a leaf. The rest of the tree indexes nodes as if they do.
+    fn has_shape(&self, k: usize) -> bool {
+        let num_keys = self.num_keys();
+        let num_children = self.children.iter()
+            .take_while(|c| c.is_some()).count();
+        Some(self.data.len()) == k.checked_mul(2)
+            && self.children.len() == self.data.len() + 1
+            && self.counts.len() == self.children.len()
+            && self.expires.len() == self.data.len()
+            && self.counts[num_children..].iter().all(|c| *c == 0)
+            && self.data[num_keys..].iter().all(|d| d.is_none())
+            && self.expires[num_keys..].iter().all(|e| e.is_none())
+            && self.children[num_children..].iter().all(|c| c.is_none())
+            && (num_children == 0 || num_children == num_keys + 1)
+    }
+
+    /// Whether the keys in the node are in strictly increasing order.
+    fn keys_in_order(&self) -> bool {
+        self.data[..self.num_keys()].windows(2).all(|pair| {
+            match (pair[0], pair[1]) {
+                (Some((a, _)), Some((b, _))) =>
+                    compare_keys(&a, &b) == Ordering::Less,
+                _                            => false,
+            }
+        })
+    }
+
+    /// Copy the keys of the node encoded in `bytes` into `keys`,
+    /// decoding it a field at a time instead of building the node.
+    /// Fails, possibly after copying some keys, if the bytes don't
+    /// decode as a node that `has_shape(k)` would accept.
+    fn decode_keys(bytes: &[u8], k: usize, keys: &mut Vec<i64>)
+                   -> Result<(), ()> {
+        fn next<T: Deserialize>(bytes: &mut &[u8]) -> Result<T, ()> {
+            deserialize_from(bytes, Infinite).map_err(|_| ())
+        }
+        let mut bytes = bytes;
+        let slots = 2 * k as u64;
+        // The fields in the order they are declared, each vector
+        // prefixed with its length.
+        if try!(next::<u64>(&mut bytes)) != slots + 1 {
+            return Err(());
+        }
+        let mut num_children = 0;
+        for i in 0..slots + 1 {
+            match try!(next::<Option<NodeOffset>>(&mut bytes)) {
+                Some(_) if i == num_children => num_children += 1,
+                Some(_)                      => return Err(()),
+                None                         => (),
+            }
+        }
+        if try!(next::<u64>(&mut bytes)) != slots + 1 {
+            return Err(());
+        }
+        for i in 0..slots + 1 {
+            if try!(next::<u64>(&mut bytes)) != 0 && i >= num_children {
+                return Err(());
+            }
+        }
+        try!(next::<NodeOffset>(&mut bytes));
+        if try!(next::<u64>(&mut bytes)) != slots {
+            return Err(());
+        }
+        let mut num_keys = 0;
+        for i in 0..slots {
+            match try!(next::<BTreeData>(&mut bytes)) {
+                Some((key, _)) if i == num_keys => {
+                    keys.push(key);
+                    num_keys += 1;
+                },
+                Some(_) => return Err(()),
+                None    => (),
+            }
+        }
+        if try!(next::<u64>(&mut bytes)) != slots {
+            return Err(());
+        }
+        for i in 0..slots {
+            if try!(next::<Option<i64>>(&mut bytes)).is_some()
+                && i >= num_keys {
+                return Err(());
+            }
+        }
+        if num_children == 0 || num_children == num_keys + 1 {
+            Ok(())
+        }
+        else {
+            Err(())
         }
     }
 
@@ -102,17 +277,325 @@ impl BTreeNode {
             None    => true,
         }
     }
+
+    /// The number of data elements actually stored in the node. Data
+    /// is packed to the left, so this is the index of the first
+    /// `None`.
+    fn num_keys(&self) -> usize {
+        self.data.iter().take_while(|d| d.is_some()).count()
+    }
+
+    /// The offset of the i-th child, or `None` if there is no such
+    /// child.
+    fn child(&self, i: usize) -> Option<NodeOffset> {
+        self.children.get(i).and_then(|c| *c)
+    }
+
+    /// Find `key` among the data stored in this node. Returns
+    /// `Ok(index)` if it is found, otherwise `Err(index)` where
+    /// `index` is both the position the key would be inserted at and
+    /// the child that should be searched for it.
+    fn find(&self, key: i64) -> Result<usize, usize> {
+        let num_keys = self.num_keys();
+        self.data[..num_keys].binary_search_by(|d| match *d {
+            Some((k, _)) => compare_keys(&k, &key),
+            None         => unreachable!(),
+        })
+    }
+
+    /// The index of the child stored at `offset`.
+    fn child_index(&self, offset: NodeOffset) -> Result<usize, BTreeError> {
+        self.children.iter().position(|c| *c == Some(offset))
+            .ok_or(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidData,
+                             "node is not a child of its parent")))
+    }
+
+    /// Whether the entry in data slot `i` has an expiry time that has
+    /// passed.
+    fn is_expired(&self, i: usize) -> bool {
+        match self.expires[i] {
+            Some(expires) => expires <= unix_millis() as i64,
+            None          => false,
+        }
+    }
+
+    /// The entries stored in this node, with their expiry times,
+    /// without the `None` padding.
+    fn entries(&self) -> Vec<Entry> {
+        self.data.iter().zip(self.expires.iter())
+            .filter_map(|(d, expires)| d.map(|(key, value)|
+                                             (key, value, *expires)))
+            .collect()
+    }
+
+    /// The offsets of this node's children without the `None`
+    /// padding.
+    fn offsets(&self) -> Vec<NodeOffset> {
+        self.children.iter().filter_map(|c| *c).collect()
+    }
+
+    /// The offsets of this node's children, each with the number of
+    /// entries in the subtree under it.
+    fn links(&self) -> Vec<(NodeOffset, u64)> {
+        self.children.iter().zip(self.counts.iter())
+            .filter_map(|(c, count)| c.map(|c| (c, *count)))
+            .collect()
+    }
+
+    /// The number of entries in this node and every node below it.
+    fn size(&self) -> u64 {
+        self.num_keys() as u64 + self.counts.iter().sum::<u64>()
+    }
+
+    /// Replace the contents of this node, padding back out to the
+    /// fixed node size with `None`.
+    fn set_contents(&mut self, entries: &[Entry],
+                    children: &[(NodeOffset, u64)]) {
+        for (i, d) in self.data.iter_mut().enumerate() {
+            *d = entries.get(i).map(|&(key, value, _)| (key, value));
+        }
+        for (i, expires) in self.expires.iter_mut().enumerate() {
+            *expires = entries.get(i).and_then(|&(_, _, expires)| expires);
+        }
+        for (i, c) in self.children.iter_mut().enumerate() {
+            *c = children.get(i).map(|&(offset, _)| offset);
+        }
+        for (i, count) in self.counts.iter_mut().enumerate() {
+            *count = children.get(i).map_or(0, |&(_, count)| count);
+        }
+    }
+}
+
+/// A summary of a node parsed by `BTree::try_parse_node_at()`.
+pub struct NodeInfo {
+    pub offset:       NodeOffset,
+    pub parent:       NodeOffset,
+    pub num_children: usize,
+    pub keys:         Vec<i64>,
+}
+
+/// The metadata of a tree file as reported by `BTree::inspect()`.
+#[derive(Debug)]
+pub struct FileInspection {
+    pub file_length:          u64,
+    pub node_length:          u64,         // Node size in the header
+    pub k:                    usize,       // Degree in the header
+    pub expected_node_length: Option<u64>, // Node size implied by `k`
+    pub root_offset:          NodeOffset,
+    pub root_valid:           bool,        // Whether the root decodes
+}
+
+/// A node that couldn't be decoded, passed to the hook set with
+/// `BTree::open_with_corruption_hook()`.
+#[derive(Debug)]
+pub struct CorruptionInfo {
+    pub offset: NodeOffset,
+    pub reason: String, // Why the node was rejected
+}
+
+/// What to do about a node that couldn't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CorruptionAction {
+    Fail,            // Return the error, as if there were no hook
+    Skip,            // Read around the node, but never write over it
+    SubstituteEmpty, // Read the node as an empty leaf everywhere
+}
+
+/// The kind of mutation recorded by an `AuditRecord`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditOp {
+    Insert,
+    Delete,
+    Update,
+}
+
+/// One entry in a tree's audit log. The timestamp is in milliseconds
+/// since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AuditRecord {
+    pub op:        AuditOp,
+    pub key:       i64,
+    pub timestamp: u64,
+}
+
+/// Iterator over the records in an audit log, oldest first. Returned
+/// by `BTree::audit_log()`.
+pub struct AuditLog {
+    reader: Option<BufReader<File>>,
+}
+
+impl Iterator for AuditLog {
+    type Item = AuditRecord;
+
+    fn next(&mut self) -> Option<AuditRecord> {
+        match self.reader {
+            // A record cut short by a crash ends the log, just like
+            // the end of the file does.
+            Some(ref mut reader) => deserialize_from(reader, Infinite).ok(),
+            None                 => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct BTreeHeader(u64, usize);
 
+/// Slots no node is using, grouped by the size of the slot. A slot is
+/// only handed out for a node of exactly the size it was freed with,
+/// so a node is never written into a slot too small to hold it.
+struct FreeList {
+    slots: BTreeMap<u64, Vec<NodeOffset>>,
+}
+
+impl FreeList {
+    fn new() -> FreeList {
+        FreeList { slots: BTreeMap::new() }
+    }
+
+    /// Record that the `length` bytes at `offset` are free.
+    fn push(&mut self, offset: NodeOffset, length: u64) {
+        self.slots.entry(length).or_insert_with(Vec::new).push(offset);
+    }
+
+    /// Take a free slot of exactly `length` bytes, if there is one.
+    fn pop(&mut self, length: u64) -> Option<NodeOffset> {
+        self.slots.get_mut(&length).and_then(|offsets| offsets.pop())
+    }
+
+    /// The number of free slots of every size.
+    fn len(&self) -> usize {
+        self.slots.values().map(|offsets| offsets.len()).sum()
+    }
+}
+
+/// Node writes held in memory by `BTree::buffer_writes()`, keyed by
+/// the offset of the slot they are for.
+struct WriteBuffer {
+    slots:     BTreeMap<NodeOffset, Vec<u8>>,
+    max_nodes: usize,
+    max_delay: Duration,
+    oldest:    Option<Instant>, // When the oldest unflushed write was made
+}
+
+impl WriteBuffer {
+    /// Copy the buffered contents of the slot at `offset` into
+    /// `buffer`. Returns false if the slot isn't buffered.
+    fn get(&self, offset: NodeOffset, buffer: &mut [u8]) -> bool {
+        match self.slots.get(&offset) {
+            Some(bytes) => {
+                buffer.copy_from_slice(&bytes[..buffer.len()]);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.slots.len() >= self.max_nodes
+            || self.oldest.map_or(false, |t| t.elapsed() >= self.max_delay)
+    }
+}
+
+/// The operation ids most recently applied by
+/// `BTree::insert_idempotent()`, kept in a sidecar file as a ring of
+/// `window` slots behind a count of the ids ever recorded. The next
+/// id goes in slot `recorded % window`.
+#[derive(Serialize, Deserialize)]
+struct OpRing {
+    recorded: u64,
+    ids:      Vec<u64>,
+}
+
+/// An open operation id sidecar, see `BTree::track_op_ids()`.
+struct OpLog {
+    file: File,
+    ring: OpRing,
+    seen: HashSet<u64>, // The ids in the ring
+}
+
+impl OpLog {
+    fn open(name: &str, window: usize) -> Result<OpLog, IOError> {
+        if window == 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "window must be at least 1"));
+        }
+        let mut file = try!(OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .open(Path::new(&format!("{}.ops", name))));
+        let file_length = try!(file.metadata()).len();
+        let ring = if file_length == 0 {
+            let ring = OpRing { recorded: 0, ids: vec![0; window] };
+            try!(file.write_all(&serialize(&ring, Infinite).unwrap()[..]));
+            ring
+        }
+        else {
+            let ring: OpRing = try!(
+                deserialize_from(&mut file, Bounded(file_length))
+                    .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                              "failed to decode op id log")));
+            if ring.ids.len() != window {
+                return Err(IOError::new(IOErrorKind::InvalidInput,
+                                        "op id log has a different window"));
+            }
+            ring
+        };
+        let live = cmp::min(ring.recorded, window as u64) as usize;
+        let seen = ring.ids[..live].iter().cloned().collect();
+        Ok(OpLog { file: file, ring: ring, seen: seen })
+    }
+
+    /// Add `op_id` to the ring, pushing out the oldest id once the
+    /// ring is full. The slot is written before the count, so a crash
+    /// between the two loses one id either way: until the ring fills,
+    /// the slot is past the `recorded` ids and `op_id` is lost; after
+    /// that, the slot held the oldest id, which is lost instead, and
+    /// `op_id` is kept.
+    fn record(&mut self, op_id: u64) -> Result<(), IOError> {
+        let window = self.ring.ids.len() as u64;
+        let slot = (self.ring.recorded % window) as usize;
+        if self.ring.recorded >= window {
+            self.seen.remove(&self.ring.ids[slot]);
+        }
+        self.ring.ids[slot] = op_id;
+        self.seen.insert(op_id);
+        self.ring.recorded += 1;
+        // `recorded`, then the length of `ids`, then the slots.
+        let width = serialized_size(&op_id);
+        try!(self.file.seek(SeekFrom::Start(2 * width + slot as u64 * width)));
+        try!(self.file.write_all(&serialize(&op_id, Infinite).unwrap()[..]));
+        try!(self.file.seek(SeekFrom::Start(0)));
+        self.file.write_all(&serialize(&self.ring.recorded, Infinite)
+                            .unwrap()[..])
+    }
+}
+
 /// Simple first cut, map uuid keys to globs of text, no generics.
-pub struct BTree {
+///
+/// Nodes are kept in a file unless the tree was made with
+/// `BTree::empty()`, which keeps them in memory instead.
+pub struct BTree<S: Storage = File> {
     root: BTreeNode,
-    file: File,       // The file that the btree is stored in
+    storage: S,       // Where the btree is stored
     node_length: u64, // The size of a serialized BTreeNode
     k: usize,
+    audit_log: Option<File>,    // Sidecar log of mutations, if enabled
+    free_list: FreeList,        // Slots no node is using
+    node_buffer: Vec<u8>,       // Reused by `with_node_keys()`
+    key_buffer: Vec<i64>,       // Reused by `with_node_keys()`
+    read_only: bool,            // Opened with `open_read_only()`
+    on_corruption: Option<Box<Fn(CorruptionInfo) -> CorruptionAction>>,
+    skipped: HashSet<NodeOffset>,      // Slots the hook said to skip
+    substituted: HashSet<NodeOffset>,  // Slots the hook emptied
+    recount: bool,                     // A stand-in has been written
+    write_buffer: Option<WriteBuffer>, // Set by `buffer_writes()`
+    height: Option<usize>,             // Levels in the tree, once known
+    op_log: Option<OpLog>,             // Set by `track_op_ids()`
+    cache_root: bool,                  // Whether `root` is kept
+    bytes_written: u64,  // Node bytes written to storage, for
+    bytes_inserted: u64, // `write_amplification()`
 }
 
 impl BTree {
@@ -125,11 +608,16 @@ impl BTree {
     /// * `name` - A string slice that holds the name of the BTree.
     ///            The name is the name of the file that holds the
     ///            data.
-    /// * `k`    - The BTree will have 2*k children per node and
-    ///            2*k + 1 data elements per node.
+    /// * `k`    - The BTree will have 2*k + 1 children per node and
+    ///            2*k data elements per node. Every node except the
+    ///            root holds at least k data elements.
     pub fn new(name: &str, k: usize) -> Result<BTree, IOError> {
+        if k == 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "k must be at least 1"));
+        }
         let btree_path = Path::new(name);
-        let mut file = match OpenOptions::new()
+        let file = match OpenOptions::new()
             .write(true)
             .read(true)
             .create_new(true)
@@ -137,24 +625,7 @@ impl BTree {
                 Ok(file) => file,
                 Err(why) => return Err(why),
             };
-        let root_node = BTreeNode::new(k, 0);
-        let header = BTreeHeader(serialized_size(&root_node), k);
-        let serialized_header = serialize(&header, Infinite)
-            .unwrap();
-        match (&mut file).write_all(&serialized_header) {
-            Ok(_)        =>{
-                let mut tree = BTree {
-                    file: file,
-                    node_length: serialized_size(&BTreeNode::new(k, 0)),
-                    root: BTreeNode::new(k, 0),
-                    k: k };
-                // panic if it couldn't be stored.
-                root_node.store_node( &mut tree,
-                                       serialized_size(&header)).unwrap();
-                Ok(tree)
-            },
-            Err(ioerror) => Err(ioerror),
-        }
+        BTree::create(file, k)
     }
 
     /// Open an existing BTree.
@@ -165,41 +636,539 @@ impl BTree {
     ///            `BTree::new()`
     pub fn open(name: &str) -> Result<BTree, IOError> {
         let btree_path = Path::new(name);
-        let mut file = match OpenOptions::new()
+        let file = match OpenOptions::new()
             .write(true)
             .read(true)
             .open(btree_path) {
                 Ok(file) => file,
                 Err(why) => return Err(why),
             };
-        // This may not be the most efficient way to do this, but it
-        // is probably the least amount of code. 32 bytes is probably
-        // big enough to read the whole header, but it is just a
-        // guess.
-        let header: BTreeHeader;
-        {
-            let mut reader = BufReader::new(&mut file);
-            header =
-                deserialize_from(&mut reader, Infinite).unwrap();
+        BTree::from_storage(file, false)
+    }
+
+    /// Open an existing BTree for reading only. The file is opened
+    /// without write access, and `insert()`, `delete()` and
+    /// `compare_and_swap()` fail immediately with a `PermissionDenied`
+    /// error.
+    pub fn open_read_only(name: &str) -> Result<BTree, IOError> {
+        let file = try!(OpenOptions::new()
+                        .read(true)
+                        .open(Path::new(name)));
+        BTree::from_storage(file, true)
+    }
+
+    /// Create a new BTree, as `BTree::new()`, that records every
+    /// insert and delete in an audit log. The log is a sidecar file
+    /// named `<name>.audit`.
+    ///
+    /// The log is for people to read, not for recovery. It is not
+    /// consulted when the tree is opened, and it is never synced.
+    /// The cost is one extra write of about 25 bytes per successful
+    /// mutation, on top of the node writes the mutation already
+    /// does.
+    pub fn new_with_audit_log(name: &str, k: usize)
+                              -> Result<BTree, IOError> {
+        let mut tree = try!(BTree::new(name, k));
+        tree.audit_log = Some(try!(BTree::open_audit_log(name)));
+        Ok(tree)
+    }
+
+    /// Open an existing BTree, as `BTree::open()`, appending to its
+    /// audit log (which is created if it doesn't exist yet).
+    pub fn open_with_audit_log(name: &str) -> Result<BTree, IOError> {
+        let mut tree = try!(BTree::open(name));
+        tree.audit_log = Some(try!(BTree::open_audit_log(name)));
+        Ok(tree)
+    }
+
+    /// Open an existing BTree, as `BTree::open()`, calling `hook`
+    /// whenever a node read from the file can't be decoded. The hook
+    /// decides whether the read fails or the node is treated as
+    /// empty, so a partly damaged tree can still serve the entries
+    /// that aren't damaged.
+    ///
+    /// Either way the node reads as empty, which hides every entry in
+    /// it and in the subtree below it, silently: lookups report those
+    /// keys as missing and scans leave them out. The two differ in
+    /// what writes may do:
+    ///
+    /// * `SubstituteEmpty` - the stand-in is a real empty leaf. An
+    ///   insert or delete that touches it writes it back over the
+    ///   damaged slot, so whatever could still have been salvaged
+    ///   from the slot is lost. The entry counts above it are then
+    ///   recounted to match.
+    /// * `Skip` - the slot is left alone. Any write to it fails with
+    ///   `InvalidData` until it is replaced with `write_node_bytes()`,
+    ///   and `rebuild_free_list()` never hands it out. An insert or
+    ///   delete that would change the node fails before writing
+    ///   anything, and a delete that would merge with it or borrow
+    ///   from it leaves its sibling short instead.
+    ///
+    /// With either, the nodes below the damaged one can no longer be
+    /// found, and `rebuild_free_list()` counts them as free space to
+    /// be reused. Copy out what is needed rather than repairing a
+    /// damaged tree in place.
+    ///
+    /// The root is read when the tree is opened, before there is a
+    /// hook to ask, so a damaged root still makes this fail.
+    pub fn open_with_corruption_hook<F>(name: &str, hook: F)
+                                        -> Result<BTree, IOError>
+        where F: Fn(CorruptionInfo) -> CorruptionAction + 'static {
+        let mut tree = try!(BTree::open(name));
+        tree.on_corruption = Some(Box::new(hook));
+        Ok(tree)
+    }
+
+    /// Estimate the size in bytes of a file holding `num_entries`
+    /// entries in a tree of degree `k`, for provisioning storage and
+    /// comparing values of `k` before building the tree.
+    ///
+    /// The estimate assumes every node is full, as in a bulk-loaded
+    /// tree. Keys in internal nodes are real entries, so the internal
+    /// levels are just more full nodes: a packed tree needs one node
+    /// per `2 * k` entries. Every node takes a full slot on disk
+    /// whatever it holds, and there is no footer, so the size is the
+    /// header plus the slots. A tree built by inserting one entry at a
+    /// time keeps nodes between half and completely full, and so can
+    /// be up to about twice this size.
+    ///
+    /// A `k` of 0, which `BTree::new()` rejects, is treated as 1. A
+    /// size too large for a `u64` is reported as `u64::MAX`.
+    pub fn estimate_size(k: usize, num_entries: u64) -> u64 {
+        let k = cmp::max(k, 1);
+        let per_node = (k as u64).saturating_mul(2);
+        let num_nodes = cmp::max(num_entries / per_node
+                                 + if num_entries % per_node == 0 { 0 }
+                                   else { 1 }, 1);
+        BTreeNode::checked_length(k)
+            .and_then(|node_length| node_length.checked_mul(num_nodes))
+            .and_then(|nodes| nodes.checked_add(
+                serialized_size(&BTreeHeader(0, 0))))
+            .unwrap_or(u64::max_value())
+    }
+
+    /// Make the tree `new_name` the tree `old_name`, replacing it in
+    /// one step, for swapping in a rebuilt tree. The new tree is
+    /// checked to open and synced first, then renamed over the old
+    /// one, and on Unix the directory is synced so the rename
+    /// survives a crash. Both names must be in the same filesystem.
+    /// Handles on the new tree should be dropped first, so that
+    /// nothing is left in a write buffer. Sidecars such as the audit
+    /// log keep their old names.
+    ///
+    /// On Unix the rename is atomic: anyone opening `old_name` gets
+    /// either the old tree or the new one. Trees already open on the
+    /// old file keep reading it, unchanged, until they are dropped;
+    /// they never see the new tree. On Windows the rename is not
+    /// guaranteed to be atomic, and fails while anything has
+    /// `old_name` open.
+    pub fn replace(old_name: &str, new_name: &str) -> Result<(), IOError> {
+        try!(BTree::open_read_only(new_name));
+        // Windows only flushes a file opened for writing.
+        try!(try!(OpenOptions::new()
+                  .write(true)
+                  .open(Path::new(new_name))).sync_all());
+        try!(fs::rename(Path::new(new_name), Path::new(old_name)));
+        if cfg!(unix) {
+            let directory = match Path::new(old_name).parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _                                       => Path::new("."),
+            };
+            try!(try!(File::open(directory)).sync_all());
+        }
+        Ok(())
+    }
+
+    fn open_audit_log(name: &str) -> Result<File, IOError> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(Path::new(&format!("{}.audit", name)))
+    }
+
+    /// Read the header of a tree file without opening the tree. This
+    /// is a diagnostic for files that `open()` rejects: it reports
+    /// what the header says and whether the root decodes, but a root
+    /// that doesn't decode is not an error.
+    ///
+    /// There is no footer in this layout. The root always sits
+    /// directly after the header, so `root_offset` is reported
+    /// instead. `expected_node_length` is `None` if `k` is 0 or too
+    /// large for a node of that degree to have a size.
+    pub fn inspect(name: &str) -> Result<FileInspection, IOError> {
+        let mut file = try!(OpenOptions::new()
+                            .read(true)
+                            .open(Path::new(name)));
+        let file_length = try!(file.metadata()).len();
+        let header: BTreeHeader = try!(deserialize_from(&mut file, Infinite)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                      "Failed to decode header")));
+        let root_offset = serialized_size(&header);
+        let BTreeHeader(node_length, k) = header;
+
+        // Don't trust the header enough to allocate a buffer for a
+        // root that can't be in the file.
+        let root_end = root_offset.checked_add(node_length);
+        let root_valid = root_end.map_or(false, |end| end <= file_length) && {
+            let mut encoded_node = vec![0; node_length as usize];
+            try!(file.seek(SeekFrom::Start(root_offset)));
+            try!(file.read_exact(&mut encoded_node[..]));
+            match deserialize::<BTreeNode>(&encoded_node[..]) {
+                Ok(root) => k.checked_mul(2) == Some(root.data.len())
+                    && root.children.len() == root.data.len() + 1,
+                Err(_)   => false,
+            }
+        };
+
+        Ok(FileInspection {
+            file_length:          file_length,
+            node_length:          node_length,
+            k:                    k,
+            expected_node_length: BTreeNode::checked_length(k),
+            root_offset:          NodeOffset(root_offset),
+            root_valid:           root_valid,
+        })
+    }
+
+}
+
+impl BTree<MemoryStorage> {
+    /// Create a new BTree held entirely in memory, with `k` as in
+    /// `BTree::new()`. There is no file behind it, so nothing touches
+    /// the filesystem and the tree is gone once it is dropped.
+    pub fn empty(k: usize) -> Result<BTree<MemoryStorage>, IOError> {
+        BTree::create(MemoryStorage::new(), k)
+    }
+
+    /// Write this tree to a new file called `name`, which can then be
+    /// opened with `BTree::open()`. An in-memory tree already uses the
+    /// file layout, so this is a single write of everything built so
+    /// far followed by one fsync. Fails if `name` already exists.
+    pub fn persist_to(&mut self, name: &str) -> Result<(), IOError> {
+        try!(self.flush());
+        let mut file = try!(OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(Path::new(name)));
+        try!(file.write_all(self.storage.as_bytes()));
+        file.sync_all()
+    }
+}
+
+impl<'a> BTree<&'a [u8]> {
+    /// Open a tree over the bytes of a tree file, such as one built
+    /// into the binary with `include_bytes!` or a read-only mapping
+    /// of the file. The tree is read only, as with
+    /// `BTree::open_read_only()`, and nothing touches the filesystem.
+    pub fn open_slice(bytes: &'a [u8]) -> Result<BTree<&'a [u8]>, IOError> {
+        BTree::from_storage(bytes, true)
+    }
+}
+
+impl<S: Storage> BTree<S> {
+    /// Create a new BTree in `storage`, which should be empty. This is
+    /// `BTree::new()` for storage other than a file of its own, such
+    /// as a `MirrorStorage` over two files.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Where the tree's header and nodes are written.
+    /// * `k`       - The order of the tree, as for `BTree::new()`.
+    pub fn create_in(storage: S, k: usize) -> Result<BTree<S>, IOError> {
+        BTree::create(storage, k)
+    }
+
+    /// Open the BTree already held in `storage`, as `BTree::open()`.
+    pub fn open_in(storage: S) -> Result<BTree<S>, IOError> {
+        BTree::from_storage(storage, false)
+    }
+
+    /// Write the header and an empty root to `storage`, which should
+    /// be empty, and return a tree over it.
+    fn create(storage: S, k: usize) -> Result<BTree<S>, IOError> {
+        if k == 0 {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "k must be at least 1"));
+        }
+        let header = BTreeHeader(BTreeNode::max_length(k), k);
+        let serialized_header = serialize(&header, Infinite)
+            .unwrap();
+        let mut tree = BTree::from_parts(storage, header.0, k, false);
+        try!(tree.storage.put(&serialized_header, NodeOffset(0)));
+        let root_node = BTreeNode::new(k, NodeOffset(0));
+        try!(root_node.store_node(&mut tree,
+                                  NodeOffset(serialized_size(&header))));
+        Ok(tree)
+    }
+
+    /// Read the header and root node of a tree already in `storage`.
+    fn from_storage(mut storage: S, read_only: bool)
+                    -> Result<BTree<S>, IOError> {
+        let storage_length = try!(storage.length());
+        let mut header_bytes =
+            vec![0; serialized_size(&BTreeHeader(0, 0)) as usize];
+        if storage_length < header_bytes.len() as u64 {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "Failed to decode header"));
         }
+        try!(storage.get(NodeOffset(0), &mut header_bytes[..]));
+        let header: BTreeHeader = match deserialize(&header_bytes[..]) {
+            Ok(header) => header,
+            Err(_)     => return Err(
+                IOError::new(IOErrorKind::InvalidData,
+                             "Failed to decode header")),
+        };
         let BTreeHeader(node_length, k) = header;
-        let mut tree = BTree { file: file,
-                               node_length: node_length, k: k,
-                               root: BTreeNode::new(k, 0)};
-        match BTreeNode::load_node(&mut tree, serialized_size(&header)) {
-            Ok(root)                     =>
-                Ok(BTree { root: root, .. tree }),
+        // Files written before child pointers carried entry counts,
+        // or before entries could expire, have smaller nodes. A node
+        // is larger than `k` bytes, so a garbage header with a huge
+        // `k` is rejected before the root is sized for it.
+        if k as u64 > storage_length
+            || BTreeNode::checked_length(k) != Some(node_length) {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "node size doesn't match this version"));
+        }
+        let mut tree = BTree::from_parts(storage, node_length, k, read_only);
+        let root_offset = NodeOffset(serialized_size(&header));
+        match BTreeNode::load_node(&mut tree, root_offset) {
+            Ok(root)                     => {
+                tree.root = root;
+                Ok(tree)
+            },
             Err(BTreeError::IO(ioerror)) =>
                 Err(ioerror),
             Err(_)                       =>
                 Err(IOError::new(IOErrorKind::InvalidData,
                                  "Failed to decode node")),
-            
         }
     }
 
-    /// insert a node into the BTree 
-    pub fn insert(self, key: i64, data: BTreeData) -> Result<(), BTreeError> {
+    /// A tree over `storage` with an empty root in memory. The caller
+    /// loads or stores the real root.
+    fn from_parts(storage: S, node_length: u64, k: usize, read_only: bool)
+                  -> BTree<S> {
+        BTree { storage: storage,
+                node_length: node_length, k: k,
+                root: BTreeNode::new(k, NodeOffset(0)),
+                audit_log: None,
+                free_list: FreeList::new(),
+                node_buffer: vec![],
+                key_buffer: vec![],
+                read_only: read_only,
+                on_corruption: None,
+                skipped: HashSet::new(),
+                substituted: HashSet::new(),
+                recount: false,
+                write_buffer: None,
+                height: None,
+                op_log: None,
+                cache_root: true,
+                bytes_written: 0,
+                bytes_inserted: 0 }
+    }
+
+    /// Replay the audit log from the beginning. If the tree was not
+    /// opened with an audit log the iterator is empty.
+    pub fn audit_log(&mut self) -> Result<AuditLog, IOError> {
+        let reader = match self.audit_log {
+            Some(ref log) => {
+                // The log is opened for appending, so moving the
+                // cursor of the clone doesn't affect later writes.
+                let mut file = try!(log.try_clone());
+                try!(file.seek(SeekFrom::Start(0)));
+                Some(BufReader::new(file))
+            },
+            None => None,
+        };
+        Ok(AuditLog { reader: reader })
+    }
+
+    /// Append a record for a mutation of `key` to the audit log, if
+    /// there is one.
+    fn audit(&mut self, op: AuditOp, key: i64) -> Result<(), IOError> {
+        let log = match self.audit_log {
+            Some(ref mut log) => log,
+            None              => return Ok(()),
+        };
+        let record = AuditRecord {
+            op:        op,
+            key:       key,
+            timestamp: unix_millis(),
+        };
+        let encoded_record = serialize(&record, Infinite).unwrap();
+        log.write_all(&encoded_record[..])
+    }
+
+    /// Bookkeeping after a successful mutation: record it in the audit
+    /// log, then flush buffered writes if they are due.
+    fn finish(&mut self, op: AuditOp, key: i64) -> Result<(), IOError> {
+        match op {
+            AuditOp::Insert | AuditOp::Update =>
+                self.bytes_inserted += mem::size_of::<(i64, u64)>() as u64,
+            AuditOp::Delete => (),
+        }
+        try!(self.audit(op, key));
+        if self.recount {
+            try!(self.repair_counts().map_err(io_error));
+        }
+        self.flush_if_due()
+    }
+
+    fn flush_if_due(&mut self) -> Result<(), IOError> {
+        let due = self.write_buffer.as_ref().map_or(false, |b| b.is_due());
+        if due {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    /// Hold node writes in memory rather than writing each one to
+    /// storage as it happens. Buffered writes are flushed once
+    /// `max_nodes` slots are waiting, or by the first mutation after
+    /// the oldest has waited `max_delay`. A node that changes several
+    /// times before a flush is only written once. Reads see buffered
+    /// writes.
+    ///
+    /// Anything not yet flushed is lost if the process crashes, and
+    /// since the slots are flushed in offset order rather than the
+    /// order they were written, a crash part way through a flush can
+    /// leave a mix of old and new nodes. There is no timer: a quiet
+    /// tree keeps its writes until the next mutation, `flush()` or
+    /// drop, so the window is `max_delay` plus the time to the next
+    /// mutation. Dropping the tree flushes, but ignores any error, so
+    /// call `flush()` to find out whether the writes reached storage.
+    /// Calling this again changes the limits and keeps what is
+    /// already buffered.
+    pub fn buffer_writes(&mut self, max_nodes: usize, max_delay: Duration) {
+        let slots = match self.write_buffer.take() {
+            Some(buffer) => buffer.slots,
+            None         => BTreeMap::new(),
+        };
+        let oldest = if slots.is_empty() { None } else { Some(Instant::now()) };
+        self.write_buffer = Some(WriteBuffer { slots: slots,
+                                               max_nodes: max_nodes,
+                                               max_delay: max_delay,
+                                               oldest: oldest });
+    }
+
+    /// Write every buffered node out to storage. Does nothing unless
+    /// `buffer_writes()` was called.
+    pub fn flush(&mut self) -> Result<(), IOError> {
+        let (mut slots, oldest) = match self.write_buffer {
+            Some(ref mut buffer) =>
+                (mem::replace(&mut buffer.slots, BTreeMap::new()),
+                 buffer.oldest.take()),
+            None => return Ok(()),
+        };
+        let offsets: Vec<NodeOffset> = slots.keys().cloned().collect();
+        for offset in offsets {
+            if let Err(err) = self.storage.put(&slots[&offset][..], offset) {
+                // Reads already see the slots that weren't written, so
+                // keep them buffered for the next flush to retry.
+                if let Some(ref mut buffer) = self.write_buffer {
+                    buffer.slots = slots;
+                    buffer.oldest = oldest;
+                }
+                return Err(err);
+            }
+            let bytes = slots.remove(&offset).unwrap();
+            self.bytes_written += bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Bytes of nodes written to storage for every byte of entries
+    /// inserted or updated, since the tree was opened or
+    /// `reset_write_amplification()` was last called. Each entry is
+    /// counted as the 16 bytes of its key and value. Every write of a
+    /// node writes the whole slot, and deletes write nodes without
+    /// adding any entries, so expect a figure well above 1. Writes
+    /// held by `buffer_writes()` count once they are flushed. Returns
+    /// 0 if nothing has been inserted or updated.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_inserted == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.bytes_inserted as f64
+    }
+
+    /// Start a new window for `write_amplification()`.
+    pub fn reset_write_amplification(&mut self) {
+        self.bytes_written = 0;
+        self.bytes_inserted = 0;
+    }
+
+    /// Choose whether to keep a copy of the root node in memory, as
+    /// the tree does by default. Without it the root is read from
+    /// storage at the start of every operation, one more node read
+    /// each, in exchange for not holding a node of up to `2k` entries
+    /// for as long as the tree is open. Turning the copy back on
+    /// reads the root once.
+    pub fn set_root_cache(&mut self, enabled: bool) -> Result<(), BTreeError> {
+        if enabled && !self.cache_root {
+            let root = try!(self.root_node());
+            self.root = root;
+        }
+        else if !enabled {
+            self.root = BTreeNode { children: vec![], counts: vec![],
+                                    parent: NodeOffset(0), data: vec![],
+                                    expires: vec![] };
+        }
+        self.cache_root = enabled;
+        Ok(())
+    }
+
+    /// Write a slot, or buffer the write if `buffer_writes()` is on.
+    /// Fails for a damaged slot the corruption hook said to skip.
+    fn put_slot(&mut self, offset: NodeOffset, bytes: Vec<u8>)
+                -> Result<(), IOError> {
+        if self.skipped.contains(&offset) {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "won't overwrite a skipped node"));
+        }
+        match self.write_buffer {
+            Some(ref mut buffer) => {
+                if buffer.oldest.is_none() {
+                    buffer.oldest = Some(Instant::now());
+                }
+                buffer.slots.insert(offset, bytes);
+                Ok(())
+            },
+            None => {
+                try!(self.storage.put(&bytes[..], offset));
+                self.bytes_written += bytes.len() as u64;
+                Ok(())
+            },
+        }
+    }
+
+    /// Read a slot, from the write buffer if it holds one for `offset`.
+    fn get_slot(&mut self, offset: NodeOffset, buffer: &mut [u8])
+                -> Result<(), IOError> {
+        let buffered = self.write_buffer.as_ref()
+            .map_or(false, |b| b.get(offset, buffer));
+        if buffered { Ok(()) } else { self.storage.get(offset, buffer) }
+    }
+
+    /// The length of storage, counting buffered writes past its end.
+    fn storage_length(&mut self) -> Result<u64, IOError> {
+        let stored = try!(self.storage.length());
+        let buffered = self.write_buffer.as_ref()
+            .and_then(|b| b.slots.keys().next_back().cloned())
+            .map_or(0, |NodeOffset(last)| last + self.node_length);
+        Ok(cmp::max(stored, buffered))
+    }
+
+    /// Insert a key and its value into the BTree. Fails with
+    /// `BTreeError::Exists` if the key is already in the tree.
+    ///
+    /// Every child pointer records how many entries are under it (see
+    /// `rank()`), so besides the leaf that takes the key, each node
+    /// on the path up to the root is rewritten to bump its count:
+    /// one extra node write per level of the tree. `delete()` pays the
+    /// same to lower them.
+    pub fn insert(&mut self, key: i64, value: u64) -> Result<(), BTreeError> {
         // For the first cut, I will assume the data is all of a fixed
         // size, thus We can just do insert/delete/update without much
         // difficulty.
@@ -209,39 +1178,2596 @@ impl BTree {
         // difficulty of needing to re-write large chunks of the tree
         // whenever we do a delete (such as if the tree is mapped to a
         // flat array). Instead we just change the "pointers."
-        Err(BTreeError::NotFound)
+        try!(self.check_writable());
+        try!(self.insert_entry(key, value, None));
+        self.finish(AuditOp::Insert, key).map_err(BTreeError::IO)
     }
 
-    fn search(&mut self, node: BTreeNode, key: i64)
-              -> Result<BTreeData, BTreeError> {
-        for (i, data) in node.data.enumerate() {
-            match data {
-                Some((k, v)) => {
-                    if key == k {
-                        return Ok(data);
-                    }
-                    else if key < k {
-                        break; // ???
-                    }
-                },
-                None => break,
-            }
+    /// Insert a key and its value as `insert()` does, with an expiry
+    /// time in milliseconds since the Unix epoch. From then on
+    /// `lookup()` treats the entry as absent, but it stays in the tree,
+    /// and is still seen by scans and still blocks inserting the key
+    /// again, until `sweep_expired()` removes it.
+    pub fn insert_with_expiry(&mut self, key: i64, value: u64, expires: i64)
+                              -> Result<(), BTreeError> {
+        try!(self.check_writable());
+        try!(self.insert_entry(key, value, Some(expires)));
+        self.finish(AuditOp::Insert, key).map_err(BTreeError::IO)
+    }
+
+    /// Delete every entry whose expiry time is at or before `now`, in
+    /// milliseconds since the Unix epoch, and return how many were
+    /// deleted. Each one is deleted, and audited, as by `delete()`.
+    /// It reads every node in the tree to find them.
+    pub fn sweep_expired(&mut self, now: i64) -> Result<u64, BTreeError> {
+        try!(self.check_writable());
+        let mut expired = vec![];
+        try!(self.for_each_node(|_, node| {
+            expired.extend(node.entries().into_iter()
+                           .filter(|&(_, _, expires)| match expires {
+                               Some(expires) => expires <= now,
+                               None          => false,
+                           })
+                           .map(|(key, _, _)| key));
+        }));
+        for &key in &expired {
+            try!(self.delete(key));
         }
-        if node.is_leaf() { Ok(None) }
-        else {
-            // load the child node at i and serch() in it for the key
-            // Can I do recursion with a mutable reference? Probably not.
+        Ok(expired.len() as u64)
+    }
+
+    /// The work of `insert()`, without the checks and bookkeeping
+    /// around it.
+    fn insert_entry(&mut self, key: i64, value: u64, expires: Option<i64>)
+                    -> Result<(), BTreeError> {
+        let (offset, node, position) = try!(self.find_node(key));
+        let index = match position {
+            Ok(_)  => return Err(BTreeError::Exists),
+            Err(i) => i,
+        };
+        // find_node() only stops early when the key is found, so this
+        // is a leaf.
+        try!(self.check_not_skipped(offset));
+        let mut entries = node.entries();
+        entries.insert(index, (key, value, expires));
+        let parent = node.parent;
+        try!(self.adjust_counts(offset, parent, true));
+        self.store_contents(offset, node, entries, vec![])
+    }
+
+    /// Insert a key and its value as `insert()` does, then return the
+    /// height of the tree. The height only grows when the root
+    /// splits, so comparing the result with the height before the
+    /// insert shows when a tree is getting too tall for its `k`.
+    pub fn insert_reporting_height(&mut self, key: i64, value: u64)
+                                   -> Result<usize, BTreeError> {
+        try!(self.insert(key, value));
+        self.height()
+    }
+
+    /// Remember the ids of the last `window` operations applied by
+    /// `insert_idempotent()`, in a sidecar file named `<name>.ops`
+    /// where `name` is the tree's name. The file is created if it
+    /// doesn't exist, and must have been created with the same
+    /// `window` if it does. It takes about 8 bytes per slot.
+    pub fn track_op_ids(&mut self, name: &str, window: usize)
+                        -> Result<(), IOError> {
+        self.op_log = Some(try!(OpLog::open(name, window)));
+        Ok(())
+    }
+
+    /// Insert a key and its value as `insert()` does, unless the
+    /// operation `op_id` has already been applied. Returns whether
+    /// the insert was applied this time. Fails unless
+    /// `track_op_ids()` has been called.
+    ///
+    /// Only the last `window` ids are remembered, so a replay that
+    /// arrives after `window` newer operations is applied again, and
+    /// then fails with `BTreeError::Exists` unless the key has since
+    /// been deleted. An insert that fails is not recorded and can be
+    /// retried. The id is recorded after the insert, and the sidecar
+    /// is never synced, so a crash can lose the record of an insert
+    /// that was applied; its replay then fails with `Exists` too.
+    pub fn insert_idempotent(&mut self, op_id: u64, key: i64, value: u64)
+                             -> Result<bool, BTreeError> {
+        try!(self.check_writable());
+        let seen = match self.op_log {
+            Some(ref log) => log.seen.contains(&op_id),
+            None          => return Err(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidInput,
+                             "operation ids aren't being tracked"))),
+        };
+        if seen {
+            return Ok(false);
+        }
+        try!(self.insert(key, value));
+        if let Some(ref mut log) = self.op_log {
+            try!(log.record(op_id).map_err(BTreeError::IO));
         }
+        Ok(true)
     }
-    
-    /// Find a key in the B-Tree.  I believe self must be mutable
-    /// because reading from a file mutates the handle (ie. the read
-    /// pointer moves).
-    pub fn lookup(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
-        self.search(&mut self.root, key)
+
+    /// The number of levels in the tree, counting the root, so a tree
+    /// that is only a root has height 1. The first call walks down
+    /// the left edge of the tree, reading one node per level. After
+    /// that the height is kept up to date as the root splits and
+    /// collapses, and this reads nothing.
+    pub fn height(&mut self) -> Result<usize, BTreeError> {
+        if let Some(height) = self.height {
+            return Ok(height);
+        }
+        let mut height = 1;
+        let mut node = try!(self.root_node());
+        while let Some(child) = node.child(0) {
+            node = try!(self.read_node(child));
+            height += 1;
+        }
+        self.height = Some(height);
+        Ok(height)
     }
 
-    pub fn delete(self, key: i64) -> Result<(), BTreeError> {
-        Err(BTreeError::NotFound)
+    /// Set the value of `key` to `new` only if its current value is
+    /// `expected`, where `None` means the key must not be in the tree.
+    /// Returns whether the value was set.
+    ///
+    /// The current value is the one `lookup()` sees, so an expired
+    /// entry counts as missing: it never matches `Some`, and with
+    /// `None` it is replaced by the new value, with no expiry time.
+    /// Swapping the value of a live entry keeps its expiry time.
+    ///
+    /// The tree is descended once: the node found while checking the
+    /// current value is the one that gets written. There is only ever
+    /// one handle to a tree, so nothing can change between the check
+    /// and the write.
+    pub fn compare_and_swap(&mut self, key: i64, expected: Option<u64>,
+                            new: u64) -> Result<bool, BTreeError> {
+        try!(self.check_writable());
+        let (offset, mut node, position) = try!(self.find_node(key));
+        try!(self.check_not_skipped(offset));
+        match (position, expected) {
+            (Ok(i), Some(expected))
+                if !node.is_expired(i)
+                && node.data[i] == Some((key, expected)) => {
+                node.data[i] = Some((key, new));
+                try!(self.write_node(offset, &node).map_err(BTreeError::IO));
+                try!(self.finish(AuditOp::Update, key).map_err(BTreeError::IO));
+                Ok(true)
+            },
+            (Ok(i), None) if node.is_expired(i) => {
+                node.data[i] = Some((key, new));
+                node.expires[i] = None;
+                try!(self.write_node(offset, &node).map_err(BTreeError::IO));
+                try!(self.finish(AuditOp::Insert, key).map_err(BTreeError::IO));
+                Ok(true)
+            },
+            (Err(i), None) => {
+                let mut entries = node.entries();
+                entries.insert(i, (key, new, None));
+                let parent = node.parent;
+                try!(self.adjust_counts(offset, parent, true));
+                try!(self.store_contents(offset, node, entries, vec![]));
+                try!(self.finish(AuditOp::Insert, key).map_err(BTreeError::IO));
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Find a key in the B-Tree.  I believe self must be mutable
+    /// because reading from a file mutates the handle (ie. the read
+    /// pointer moves).
+    ///
+    /// An entry whose expiry time has passed is reported as absent.
+    pub fn lookup(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        let (_, node, position) = try!(self.find_node(key));
+        let i = match position {
+            Ok(i)  => i,
+            Err(_) => return Ok(None),
+        };
+        if node.is_expired(i) { Ok(None) } else { Ok(node.data[i]) }
+    }
+
+    /// Get the entry with the smallest key strictly greater than
+    /// `key`, whether or not `key` itself is in the tree.
+    ///
+    /// Internal keys are real entries, so each node passed on the
+    /// way down offers the key just right of where the search turns
+    /// as a candidate, and anything found lower down is closer. This
+    /// reads one node per level.
+    pub fn successor(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        let mut best = None;
+        let mut node = try!(self.root_node());
+        loop {
+            // Everything under the child to the right of a match is
+            // greater than `key`.
+            let i = match node.find(key) {
+                Ok(i)  => i + 1,
+                Err(i) => i,
+            };
+            if let Some(entry) = node.data.get(i).and_then(|d| *d) {
+                best = Some(entry);
+            }
+            match node.child(i) {
+                Some(offset) => node = try!(self.read_node(offset)),
+                None         => return Ok(best),
+            }
+        }
+    }
+
+    /// Get the entry with the largest key strictly less than `key`.
+    /// This is the mirror image of `successor()`.
+    pub fn predecessor(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        let mut best = None;
+        let mut node = try!(self.root_node());
+        loop {
+            let i = match node.find(key) {
+                Ok(i) | Err(i) => i,
+            };
+            if i > 0 {
+                if let Some(entry) = node.data[i - 1] {
+                    best = Some(entry);
+                }
+            }
+            match node.child(i) {
+                Some(offset) => node = try!(self.read_node(offset)),
+                None         => return Ok(best),
+            }
+        }
+    }
+
+    /// Find the leaf that `key` would be inserted into and return how
+    /// many more entries it can take before it has to split. A key
+    /// already in an internal node is treated as belonging to the
+    /// leaf just after it.
+    ///
+    /// Only the one leaf is considered. Entries spread over a range
+    /// of keys may land in several leaves, and a split moves entries
+    /// around, so this is a hint for a single leaf and nothing more.
+    pub fn leaf_free_slots(&mut self, key: i64) -> Result<usize, BTreeError> {
+        let mut node = try!(self.root_node());
+        loop {
+            let next = match node.find(key) {
+                Ok(i)  => node.child(i + 1),
+                Err(i) => node.child(i),
+            };
+            match next {
+                Some(child) => node = try!(self.read_node(child)),
+                None        => return Ok(2*self.k - node.num_keys()),
+            }
+        }
+    }
+
+    /// Test whether any key `k` with `low <= k <= high` is in the
+    /// tree.
+    ///
+    /// Unlike a B+-tree, the keys in internal nodes here are real
+    /// entries rather than copies of separators. So if any key in a
+    /// node falls in the range the answer is yes. If none does, the
+    /// keys on either side of the range bracket exactly one child
+    /// that could hold a key in the range. That is the only child
+    /// searched, so the search follows a single path from the root
+    /// and reads at most one node per level.
+    pub fn any_in_range(&mut self, low: i64, high: i64)
+                        -> Result<bool, BTreeError> {
+        if compare_keys(&low, &high) == Ordering::Greater {
+            return Ok(false);
+        }
+        let mut node = try!(self.root_node());
+        loop {
+            let next = match node.find(low) {
+                Ok(_)  => return Ok(true),
+                Err(i) => {
+                    match node.data.get(i).and_then(|d| *d) {
+                        Some((key, _)) if compare_keys(&key, &high)
+                            != Ordering::Greater => return Ok(true),
+                        _ => node.child(i),
+                    }
+                },
+            };
+            match next {
+                Some(offset) => node = try!(self.read_node(offset)),
+                None         => return Ok(false),
+            }
+        }
+    }
+
+    /// Call `f` with the keys stored in the node at `offset`. This is
+    /// a lower-level primitive for callers that walk nodes themselves
+    /// and want to keep allocation down. The node is read into a
+    /// buffer owned by the tree and its keys are decoded straight out
+    /// of it into another, so once the buffers have grown, calls
+    /// don't allocate at all.
+    ///
+    /// A slot that doesn't hold a node of this tree is read again the
+    /// way every other read goes, so it fails, or reads as empty, just
+    /// as the corruption hook decides.
+    pub fn with_node_keys<R, F>(&mut self, offset: NodeOffset, f: F)
+                                -> Result<R, BTreeError>
+        where F: FnOnce(&[i64]) -> R {
+        self.key_buffer.clear();
+        let decoded = if offset == self.root_offset() && self.cache_root {
+            self.key_buffer.extend(self.root.data.iter()
+                                   .filter_map(|d| d.map(|(key, _)| key)));
+            true
+        }
+        else {
+            let mut bytes = mem::replace(&mut self.node_buffer, vec![]);
+            bytes.resize(self.node_length as usize, 0);
+            let read = self.get_slot(offset, &mut bytes[..]);
+            let decoded = read.is_ok()
+                && BTreeNode::decode_keys(&bytes[..], self.k,
+                                          &mut self.key_buffer).is_ok();
+            self.node_buffer = bytes;
+            try!(read.map_err(BTreeError::IO));
+            decoded
+        };
+        if !decoded {
+            let node = try!(self.read_node(offset));
+            self.key_buffer.clear();
+            self.key_buffer.extend(node.entries().iter()
+                                   .map(|&(key, _, _)| key));
+        }
+        Ok(f(&self.key_buffer[..]))
+    }
+
+    /// Iterate over the entries in the tree in order, one batch per
+    /// leaf from left to right. Each call to `next()` reads one leaf
+    /// (plus any ancestors not already on the path to it), so batches
+    /// follow the layout of the tree on disk.
+    ///
+    /// In a B-tree some entries live in internal nodes, between two
+    /// leaves. Each one is added to the end of the batch for the
+    /// leaf just before it. Concatenating the batches gives every
+    /// entry exactly once, in ascending order.
+    pub fn iter_by_leaf(&mut self) -> LeafIter<S> {
+        let root_offset = self.root_offset();
+        LeafIter { tree: self, stack: vec![], next: Some(root_offset) }
+    }
+
+    /// Walk the whole tree in order and return every key that is equal
+    /// to the key before it. A run of copies of one key is reported
+    /// once. A healthy tree never has any, since `insert()` rejects a
+    /// key that is already present. A duplicate means a bug in
+    /// splitting or merging nodes, and makes lookups of that key
+    /// depend on which copy the search happens to reach first.
+    ///
+    /// The walk follows the child pointers rather than searching by
+    /// key, so it finds copies that a lookup would never reach. Copies
+    /// that aren't next to each other in the walk are only out of
+    /// order, not equal to their predecessor, and are not reported.
+    /// It reads every node in the tree.
+    pub fn find_duplicates(&mut self) -> Result<Vec<i64>, BTreeError> {
+        let mut duplicates = vec![];
+        let mut previous = None;
+        for group in self.iter_by_leaf() {
+            for (key, _) in try!(group) {
+                if previous == Some(key) && duplicates.last() != Some(&key) {
+                    duplicates.push(key);
+                }
+                previous = Some(key);
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Get every key in the tree in ascending order, without values.
+    /// It reads every node in the tree.
+    pub fn collect_keys(&mut self) -> Result<Vec<i64>, BTreeError> {
+        let mut keys = vec![];
+        for group in self.iter_by_leaf() {
+            keys.extend(try!(group).into_iter().map(|(key, _)| key));
+        }
+        Ok(keys)
+    }
+
+    /// Write every key in the tree to `out` in ascending order, each
+    /// encoded as bincode encodes an `i64`, with nothing between
+    /// them. Unlike `collect_keys()` only one leaf's worth of keys is
+    /// held at a time, so this suits trees too big to collect.
+    pub fn export_keys<W: Write>(&mut self, out: &mut W)
+                                 -> Result<(), BTreeError> {
+        for group in self.iter_by_leaf() {
+            for (key, _) in try!(group) {
+                let encoded_key = serialize(&key, Infinite).unwrap();
+                try!(out.write_all(&encoded_key[..]).map_err(BTreeError::IO));
+            }
+        }
+        Ok(())
+    }
+
+    /// Borrow the tree for a read-only scan. See `ReadGuard`.
+    pub fn read_guard(&mut self) -> ReadGuard<S> {
+        ReadGuard { tree: self }
+    }
+
+    /// Compare two keys the same way the tree orders them
+    /// internally. Code built on top of the tree should use this
+    /// rather than `Ord` so that it stays consistent with the tree.
+    pub fn compare_keys(&self, a: &i64, b: &i64) -> Ordering {
+        compare_keys(a, b)
+    }
+
+    /// Remove a key from the BTree. Fails with `BTreeError::NotFound`
+    /// if the key is not in the tree.
+    pub fn delete(&mut self, key: i64) -> Result<(), BTreeError> {
+        try!(self.check_writable());
+        let (offset, mut node, position) = try!(self.find_node(key));
+        let index = match position {
+            Ok(i)  => i,
+            Err(_) => return Err(BTreeError::NotFound),
+        };
+        try!(self.check_not_skipped(offset));
+        if node.is_leaf() {
+            let mut entries = node.entries();
+            entries.remove(index);
+            let parent = node.parent;
+            try!(self.adjust_counts(offset, parent, false));
+            try!(self.fix_underflow(offset, node, entries, vec![]));
+        }
+        else {
+            // The key is in an internal node. Replace it with its
+            // predecessor (the largest key in the left subtree),
+            // which always lives in a leaf, and remove that from the
+            // leaf instead.
+            let mut leaf_offset = node.children[index].unwrap();
+            let mut leaf = try!(self.read_node(leaf_offset));
+            while !leaf.is_leaf() {
+                leaf_offset = leaf.children[leaf.num_keys()].unwrap();
+                leaf = try!(self.read_node(leaf_offset));
+            }
+            try!(self.check_not_skipped(leaf_offset));
+            let mut entries = leaf.entries();
+            let (key, value, expires) = match entries.pop() {
+                Some(entry) => entry,
+                // Only a leaf the corruption hook emptied is empty.
+                None        => return Err(BTreeError::IO(
+                    IOError::new(IOErrorKind::InvalidData,
+                                 "predecessor leaf is empty"))),
+            };
+            node.data[index] = Some((key, value));
+            node.expires[index] = expires;
+            try!(self.write_node(offset, &node).map_err(BTreeError::IO));
+            let parent = leaf.parent;
+            try!(self.adjust_counts(leaf_offset, parent, false));
+            try!(self.fix_underflow(leaf_offset, leaf, entries, vec![]));
+        }
+        self.finish(AuditOp::Delete, key).map_err(BTreeError::IO)
+    }
+
+    /// Fail with `PermissionDenied` if the tree was opened read-only.
+    /// Every mutation calls this before touching anything.
+    fn check_writable(&self) -> Result<(), BTreeError> {
+        if self.read_only {
+            Err(BTreeError::IO(
+                IOError::new(IOErrorKind::PermissionDenied,
+                             "the tree is opened read-only")))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Fail with `InvalidData` if `offset` is a damaged node the
+    /// corruption hook said to skip. A mutation calls this for the
+    /// node it changes before writing anything, so that it doesn't
+    /// leave the counts above that node changed when its write fails.
+    fn check_not_skipped(&self, offset: NodeOffset) -> Result<(), BTreeError> {
+        if self.skipped.contains(&offset) {
+            Err(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidData,
+                             "won't overwrite a skipped node")))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// The offset of the root node. The root always lives immediately
+    /// after the header.
+    fn root_offset(&self) -> NodeOffset {
+        NodeOffset(serialized_size(&BTreeHeader(self.node_length, self.k)))
+    }
+
+    /// Descend from the root looking for `key`. Returns the node the
+    /// key is in, or the leaf it would be inserted into, along with
+    /// that node's offset and the result of `BTreeNode::find()`.
+    fn find_node(&mut self, key: i64)
+                 -> Result<(NodeOffset, BTreeNode, Result<usize, usize>),
+                           BTreeError> {
+        let mut offset = self.root_offset();
+        let mut node = try!(self.root_node());
+        loop {
+            let position = node.find(key);
+            let next = match position {
+                Ok(_)  => None,
+                Err(i) => node.child(i),
+            };
+            match next {
+                Some(child) => {
+                    let parent = offset;
+                    offset = child;
+                    node = try!(self.read_node(offset));
+                    // The stand-in the corruption hook can substitute
+                    // for a damaged node doesn't know its parent.
+                    node.parent = parent;
+                },
+                None => return Ok((offset, node, position)),
+            }
+        }
+    }
+
+    /// Read the root node. See `read_node()`.
+    fn root_node(&mut self) -> Result<BTreeNode, BTreeError> {
+        let offset = self.root_offset();
+        self.read_node(offset)
+    }
+
+    /// Read the node at `offset`, using the in-memory copy of the root
+    /// rather than going to the file when possible.
+    fn read_node(&mut self, offset: NodeOffset) -> Result<BTreeNode, BTreeError> {
+        if offset == self.root_offset() && self.cache_root {
+            return Ok(self.root.clone());
+        }
+        match try!(self.load_or_recover(offset)) {
+            Some(node) => Ok(node),
+            None       => Ok(BTreeNode::new(self.k, NodeOffset(0))),
+        }
+    }
+
+    /// Load the node at `offset`, asking the corruption hook what to
+    /// do if it can't be decoded. Returns `None` for a node the hook
+    /// says to skip.
+    fn load_or_recover(&mut self, offset: NodeOffset)
+                       -> Result<Option<BTreeNode>, BTreeError> {
+        let err = match BTreeNode::load_node(self, offset) {
+            Ok(node) => return Ok(Some(node)),
+            Err(BTreeError::IO(err)) => err,
+            Err(err) => return Err(err),
+        };
+        let action = match self.on_corruption {
+            Some(ref hook) if err.kind() == IOErrorKind::InvalidData =>
+                hook(CorruptionInfo { offset: offset,
+                                      reason: err.to_string() }),
+            _ => CorruptionAction::Fail,
+        };
+        match action {
+            CorruptionAction::Fail => Err(BTreeError::IO(err)),
+            CorruptionAction::Skip => {
+                self.skipped.insert(offset);
+                Ok(None)
+            },
+            CorruptionAction::SubstituteEmpty => {
+                self.substituted.insert(offset);
+                Ok(Some(BTreeNode::new(self.k, NodeOffset(0))))
+            },
+        }
+    }
+
+    /// Write a node at `offset`, keeping the in-memory root up to
+    /// date.
+    fn write_node(&mut self, offset: NodeOffset, node: &BTreeNode)
+                  -> Result<(), IOError> {
+        try!(node.store_node(self, offset));
+        if offset == self.root_offset() && self.cache_root {
+            self.root = node.clone();
+        }
+        if self.substituted.remove(&offset) {
+            // The subtree the damaged node had is gone for good now,
+            // but the counts above it still include it.
+            self.recount = true;
+        }
+        Ok(())
+    }
+
+    /// Write a node to a free slot, or a new slot at the end of the
+    /// file if there are none, returning its offset.
+    fn append_node(&mut self, node: &BTreeNode) -> Result<NodeOffset, IOError> {
+        let offset = match self.free_list.pop(self.node_length) {
+            Some(offset) => offset,
+            None         => NodeOffset(try!(self.storage_length())),
+        };
+        try!(node.store_node(self, offset));
+        Ok(offset)
+    }
+
+    /// Release the slot at `offset` once no node refers to it.
+    fn free_node(&mut self, offset: NodeOffset) {
+        self.free_list.push(offset, self.node_length);
+    }
+
+    /// Call `f` with the offset and contents of every node reachable
+    /// from the root, parents before their children.
+    fn for_each_node<F>(&mut self, mut f: F) -> Result<(), BTreeError>
+        where F: FnMut(NodeOffset, &BTreeNode) {
+        let mut pending = vec![self.root_offset()];
+        while let Some(offset) = pending.pop() {
+            let node = if offset == self.root_offset() && self.cache_root {
+                self.root.clone()
+            }
+            else {
+                match try!(self.load_or_recover(offset)) {
+                    Some(node) => node,
+                    None       => continue,
+                }
+            };
+            f(offset, &node);
+            pending.extend(node.offsets().into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Rebuild the free list by scanning every node-sized slot in the
+    /// file and collecting the ones that can't be reached from the
+    /// root, other than damaged ones the corruption hook said to
+    /// skip. Returns the number of free slots found.
+    ///
+    /// The free list only lives in memory, so it is empty whenever a
+    /// tree is opened. Space freed by deletes in an earlier session
+    /// is not reused until this is called. It reads every node in the
+    /// tree.
+    pub fn rebuild_free_list(&mut self) -> Result<u64, IOError> {
+        let mut reachable = HashSet::new();
+        try!(self.for_each_node(|offset, _| { reachable.insert(offset); })
+             .map_err(io_error));
+        let file_length = try!(self.storage_length());
+        let NodeOffset(first_slot) = self.root_offset();
+        let num_slots = (file_length - first_slot) / self.node_length;
+        self.free_list = FreeList::new();
+        // Hand out the lowest offsets first.
+        for slot in (0..num_slots).rev() {
+            let offset = NodeOffset(first_slot + slot * self.node_length);
+            if !reachable.contains(&offset) && !self.skipped.contains(&offset) {
+                self.free_list.push(offset, self.node_length);
+            }
+        }
+        Ok(self.free_list.len() as u64)
+    }
+
+    /// Count the nodes in the tree by encoded size, in `buckets`
+    /// equal-width buckets spanning the smallest to the largest size
+    /// seen. Returns the lowest size in each bucket and the number of
+    /// nodes that fall into it, smallest first.
+    ///
+    /// Every node still takes up `node_length` bytes on disk. The
+    /// sizes counted here are how much of that slot the node uses,
+    /// which grows with the number of entries and children it holds.
+    /// It reads every node in the tree.
+    pub fn node_size_histogram(&mut self, buckets: usize)
+                               -> Result<Vec<(u64, u64)>, IOError> {
+        let mut sizes = vec![];
+        try!(self.for_each_node(|_, node| sizes.push(serialized_size(node)))
+             .map_err(io_error));
+        bucket_sizes(sizes, buckets)
+    }
+
+    /// Add one to (if `grew`) or take one from the count stored with
+    /// every pointer on the path from the root down to the node at
+    /// `offset`, whose parent is `parent`, for an entry added to or
+    /// removed from that node. Splits and merges below recount the
+    /// nodes they touch, so this only has to be right for the path.
+    fn adjust_counts(&mut self, offset: NodeOffset, parent: NodeOffset,
+                     grew: bool) -> Result<(), BTreeError> {
+        let root_offset = self.root_offset();
+        let (mut offset, mut parent_offset) = (offset, parent);
+        while offset != root_offset {
+            let mut parent = try!(self.read_node(parent_offset));
+            let index = try!(parent.child_index(offset));
+            if grew {
+                parent.counts[index] += 1;
+            }
+            else {
+                parent.counts[index] -= 1;
+            }
+            try!(self.write_node(parent_offset, &parent)
+                 .map_err(BTreeError::IO));
+            offset = parent_offset;
+            parent_offset = parent.parent;
+        }
+        Ok(())
+    }
+
+    /// Check the entry count stored with every child pointer against
+    /// the subtree it points to. Returns the offsets of the nodes
+    /// holding a count that is wrong. It reads every node in the
+    /// tree.
+    pub fn verify_counts(&mut self) -> Result<Vec<NodeOffset>, BTreeError> {
+        let mut wrong = vec![];
+        let root_offset = self.root_offset();
+        let root = try!(self.root_node());
+        try!(self.count_entries(root_offset, &root, &mut wrong));
+        Ok(wrong)
+    }
+
+    /// Count the entries in the subtree under `node` by reading it,
+    /// adding `offset` to `wrong` if any of its own counts is off.
+    fn count_entries(&mut self, offset: NodeOffset, node: &BTreeNode,
+                     wrong: &mut Vec<NodeOffset>) -> Result<u64, BTreeError> {
+        let mut total = node.num_keys() as u64;
+        let mut correct = true;
+        for (child_offset, count) in node.links() {
+            let child = try!(self.read_node(child_offset));
+            if self.skipped.contains(&child_offset) {
+                // A skipped node can't be counted, so take its count
+                // on trust.
+                total += count;
+                continue;
+            }
+            let actual = try!(self.count_entries(child_offset, &child, wrong));
+            correct = correct && actual == count;
+            total += actual;
+        }
+        if !correct {
+            wrong.push(offset);
+        }
+        Ok(total)
+    }
+
+    /// Recount every subtree and rewrite the nodes whose counts are
+    /// off, once the stand-in for a damaged node has been written.
+    fn repair_counts(&mut self) -> Result<(), BTreeError> {
+        let root_offset = self.root_offset();
+        let root = try!(self.root_node());
+        try!(self.recount_entries(root_offset, root));
+        self.recount = false;
+        Ok(())
+    }
+
+    /// Count the entries in the subtree under `node` as
+    /// `count_entries()` does, fixing its counts as it goes.
+    fn recount_entries(&mut self, offset: NodeOffset, node: BTreeNode)
+                       -> Result<u64, BTreeError> {
+        let mut node = node;
+        let mut total = node.num_keys() as u64;
+        let mut correct = true;
+        let links = node.links();
+        for (i, (child_offset, count)) in links.into_iter().enumerate() {
+            let child = try!(self.read_node(child_offset));
+            let actual = if self.skipped.contains(&child_offset) {
+                count
+            }
+            else {
+                try!(self.recount_entries(child_offset, child))
+            };
+            if actual != count {
+                node.counts[i] = actual;
+                correct = false;
+            }
+            total += actual;
+        }
+        if !correct {
+            try!(self.write_node(offset, &node).map_err(BTreeError::IO));
+        }
+        Ok(total)
+    }
+
+    /// Write the tree to `out` as a GraphViz DOT graph, with one box
+    /// per node labelled with its offset and keys, and an edge to each
+    /// of its children. Render it with `dot -Tsvg` to see the shape of
+    /// the tree. Every node is read and goes into the graph, so this
+    /// is for trees small enough to look at.
+    pub fn to_dot<W: Write>(&mut self, out: &mut W) -> Result<(), IOError> {
+        try!(writeln!(out, "digraph btree {{"));
+        try!(writeln!(out, "    node [shape=box];"));
+        let mut written = Ok(());
+        try!(self.for_each_node(|offset, node| {
+            if written.is_err() {
+                return;
+            }
+            let keys: Vec<String> = node.entries().iter()
+                .map(|&(key, _, _)| key.to_string())
+                .collect();
+            written = writeln!(out, "    n{} [label=\"@{}\\n{}\"];",
+                               offset.0, offset.0, keys.join(" "));
+            for child in node.offsets() {
+                if written.is_ok() {
+                    written = writeln!(out, "    n{} -> n{};",
+                                       offset.0, child.0);
+                }
+            }
+        }).map_err(io_error));
+        try!(written);
+        writeln!(out, "}}")
+    }
+
+    /// Rebuild the tree from its entries, for repairing a tree whose
+    /// shape has gone wrong (leaves at different depths, say) while
+    /// its entries are still intact. Every entry is read by following
+    /// the child pointers down from the root, and a new tree is bulk
+    /// loaded from them in order, bottom up, with every node as full as
+    /// the entries allow.
+    ///
+    /// The new nodes go in slots no live node uses, taken from the
+    /// free list and then from the end of the file, and the root is
+    /// only overwritten once everything below it has been written.
+    /// Until then the old tree is untouched, so an error or crash
+    /// part way through leaves it as it was, with some unused slots.
+    /// Once the new root is in place the old nodes are freed. The
+    /// file has to have room for a second copy of the tree while the
+    /// new one is built.
+    ///
+    /// If a key turns up more than once, the first copy in the walk is
+    /// kept. The entries are held in memory while the tree is rebuilt.
+    /// The rebuild is not recorded in the audit log.
+    pub fn rebalance(&mut self) -> Result<(), BTreeError> {
+        try!(self.check_writable());
+        let mut entries = vec![];
+        try!(self.for_each_node(|_, node| entries.extend(node.entries())));
+        // Sorting is stable, so the first copy of a duplicated key is
+        // the one that survives dedup_by_key().
+        entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+        entries.dedup_by_key(|entry| entry.0);
+
+        // The number of keys in each node of each level, leaves first.
+        // One key is held back between each pair of neighbouring nodes
+        // in a level, and those keys make up the level above.
+        let capacity = 2 * self.k;
+        let mut levels: Vec<Vec<usize>> = vec![];
+        let mut num_keys = entries.len();
+        loop {
+            // The fewest nodes that hold `num_keys`, counting the keys
+            // between them: `num_nodes * (capacity + 1) - 1`.
+            let num_nodes = (num_keys + capacity + 1) / (capacity + 1);
+            let in_nodes = num_keys - (num_nodes - 1);
+            levels.push((0..num_nodes)
+                        .map(|i| in_nodes / num_nodes
+                             + if i < in_nodes % num_nodes { 1 } else { 0 })
+                        .collect());
+            if num_nodes == 1 {
+                break;
+            }
+            num_keys = num_nodes - 1;
+        }
+
+        // Make sure the free list only holds slots no live node uses,
+        // then pick a slot for every node but the root.
+        try!(self.rebuild_free_list().map_err(BTreeError::IO));
+        let mut end = try!(self.storage_length().map_err(BTreeError::IO));
+        let mut offsets: Vec<Vec<NodeOffset>> = vec![];
+        for level in &levels[..levels.len() - 1] {
+            let mut level_offsets = vec![];
+            for _ in level {
+                level_offsets.push(match self.free_list.pop(self.node_length) {
+                    Some(offset) => offset,
+                    None         => {
+                        end += self.node_length;
+                        NodeOffset(end - self.node_length)
+                    },
+                });
+            }
+            offsets.push(level_offsets);
+        }
+        offsets.push(vec![self.root_offset()]);
+
+        let mut keys = entries;
+        let mut links: Vec<(NodeOffset, u64)> = vec![];
+        for (depth, level) in levels.iter().enumerate() {
+            // The parent of each node in this level, in order. A node
+            // with `n` keys has `n + 1` children.
+            let parents: Vec<NodeOffset> = match levels.get(depth + 1) {
+                Some(above) => above.iter().zip(offsets[depth + 1].iter())
+                    .flat_map(|(&n, &offset)| vec![offset; n + 1])
+                    .collect(),
+                None        => vec![NodeOffset(0)],
+            };
+            let (mut next_keys, mut next_links) = (vec![], vec![]);
+            let (mut key, mut link) = (0, 0);
+            for (i, &n) in level.iter().enumerate() {
+                let mut node = BTreeNode::new(self.k, parents[i]);
+                let children = if depth == 0 { &links[..] }
+                               else { &links[link..link + n + 1] };
+                node.set_contents(&keys[key..key + n], children);
+                key += n;
+                link += children.len();
+                if offsets[depth][i] == self.root_offset() {
+                    // Everything below has to be in storage before
+                    // the root points at it.
+                    try!(self.flush().map_err(BTreeError::IO));
+                }
+                try!(self.write_node(offsets[depth][i], &node)
+                     .map_err(BTreeError::IO));
+                next_links.push((offsets[depth][i], node.size()));
+                if i + 1 < level.len() {
+                    next_keys.push(keys[key]);
+                    key += 1;
+                }
+            }
+            keys = next_keys;
+            links = next_links;
+        }
+        self.height = Some(levels.len());
+        try!(self.rebuild_free_list().map_err(BTreeError::IO));
+        self.flush_if_due().map_err(BTreeError::IO)
+    }
+
+    /// Point the parent of each node in `children` at `parent`.
+    fn reparent(&mut self, children: &[(NodeOffset, u64)], parent: NodeOffset)
+                -> Result<(), BTreeError> {
+        for &(offset, _) in children {
+            let mut child = try!(self.read_node(offset));
+            if self.skipped.contains(&offset) {
+                // There's nothing readable in the slot to update.
+                continue;
+            }
+            child.parent = parent;
+            try!(self.write_node(offset, &child).map_err(BTreeError::IO));
+        }
+        Ok(())
+    }
+
+    /// Store `entries` and `children` in the node at `offset`. If
+    /// there are too many entries the node is split around its median
+    /// entry, which is pushed up into the parent, possibly splitting
+    /// the parent as well.
+    fn store_contents(&mut self, offset: NodeOffset, node: BTreeNode,
+                      entries: Vec<Entry>,
+                      children: Vec<(NodeOffset, u64)>)
+                      -> Result<(), BTreeError> {
+        let (mut offset, mut node) = (offset, node);
+        let (mut entries, mut children) = (entries, children);
+        let root_offset = self.root_offset();
+        while entries.len() > 2 * self.k {
+            let right_entries = entries.split_off(self.k + 1);
+            let median = entries.pop().unwrap();
+            let right_children = if children.is_empty() { vec![] }
+                                 else { children.split_off(self.k + 1) };
+
+            if offset == root_offset {
+                // The root has to stay at the same offset, so both
+                // halves move out to new nodes and the root is
+                // replaced by a node holding only the median.
+                let mut left = BTreeNode::new(self.k, root_offset);
+                left.set_contents(&entries, &children);
+                let left_offset = try!(self.append_node(&left)
+                                       .map_err(BTreeError::IO));
+                try!(self.reparent(&children, left_offset));
+                let mut right = BTreeNode::new(self.k, root_offset);
+                right.set_contents(&right_entries, &right_children);
+                let right_offset = try!(self.append_node(&right)
+                                        .map_err(BTreeError::IO));
+                try!(self.reparent(&right_children, right_offset));
+                let mut root = BTreeNode::new(self.k, NodeOffset(0));
+                root.set_contents(&[median],
+                                  &[(left_offset, left.size()),
+                                    (right_offset, right.size())]);
+                self.height = self.height.map(|height| height + 1);
+                return self.write_node(root_offset, &root)
+                    .map_err(BTreeError::IO);
+            }
+
+            let mut right = BTreeNode::new(self.k, node.parent);
+            right.set_contents(&right_entries, &right_children);
+            let right_offset = try!(self.append_node(&right)
+                                    .map_err(BTreeError::IO));
+            try!(self.reparent(&right_children, right_offset));
+            node.set_contents(&entries, &children);
+            try!(self.write_node(offset, &node).map_err(BTreeError::IO));
+
+            let parent_offset = node.parent;
+            let parent = try!(self.read_node(parent_offset));
+            let index = try!(parent.child_index(offset));
+            entries = parent.entries();
+            children = parent.links();
+            entries.insert(index, median);
+            children[index].1 = node.size();
+            children.insert(index + 1, (right_offset, right.size()));
+            offset = parent_offset;
+            node = parent;
+        }
+        node.set_contents(&entries, &children);
+        self.write_node(offset, &node).map_err(BTreeError::IO)
+    }
+
+    /// Store `entries` and `children` in the node at `offset` after
+    /// something has been removed from it. If the node has fallen
+    /// below k entries it borrows an entry from a sibling, or failing
+    /// that is merged with one, which removes an entry from the
+    /// parent and may leave the parent short as well.
+    fn fix_underflow(&mut self, offset: NodeOffset, node: BTreeNode,
+                     entries: Vec<Entry>,
+                     children: Vec<(NodeOffset, u64)>)
+                 -> Result<(), BTreeError> {
+        let (mut offset, mut node) = (offset, node);
+        let (mut entries, mut children) = (entries, children);
+        let root_offset = self.root_offset();
+        loop {
+            if offset == root_offset {
+                if entries.is_empty() && !children.is_empty() {
+                    // The root is empty but has a single child, which
+                    // becomes the new root. The tree gets one level
+                    // shorter.
+                    let child = try!(self.read_node(children[0].0));
+                    let grandchildren = child.links();
+                    let mut root = BTreeNode::new(self.k, NodeOffset(0));
+                    root.set_contents(&child.entries(), &grandchildren);
+                    try!(self.write_node(root_offset, &root)
+                         .map_err(BTreeError::IO));
+                    try!(self.reparent(&grandchildren, root_offset));
+                    self.free_node(children[0].0);
+                    self.height = self.height.map(|height| height - 1);
+                    return Ok(());
+                }
+                break;
+            }
+            if entries.len() >= self.k {
+                break;
+            }
+
+            let parent_offset = node.parent;
+            let mut parent = try!(self.read_node(parent_offset));
+            let mut parent_entries = parent.entries();
+            let mut parent_children = parent.links();
+            let index = try!(parent.child_index(offset));
+
+            // A sibling the corruption hook said to skip can't be
+            // written, so it can't lend an entry or be merged with.
+            let left = match index {
+                0 => None,
+                _ => {
+                    let left_offset = parent_children[index - 1].0;
+                    let left = try!(self.read_node(left_offset));
+                    if self.skipped.contains(&left_offset) { None }
+                    else { Some((left_offset, left)) }
+                },
+            };
+            if let Some((left_offset, mut left)) = left.clone() {
+                let mut left_entries = left.entries();
+                let mut left_children = left.links();
+                if left_entries.len() > self.k {
+                    // Rotate the largest entry of the left sibling up
+                    // into the parent and the separator down into
+                    // this node.
+                    entries.insert(0, parent_entries[index - 1]);
+                    parent_entries[index - 1] = left_entries.pop().unwrap();
+                    if let Some(moved) = left_children.pop() {
+                        children.insert(0, moved);
+                        try!(self.reparent(&[moved], offset));
+                    }
+                    left.set_contents(&left_entries, &left_children);
+                    try!(self.write_node(left_offset, &left)
+                         .map_err(BTreeError::IO));
+                    node.set_contents(&entries, &children);
+                    try!(self.write_node(offset, &node)
+                         .map_err(BTreeError::IO));
+                    parent_children[index - 1].1 = left.size();
+                    parent_children[index].1 = node.size();
+                    parent.set_contents(&parent_entries, &parent_children);
+                    return self.write_node(parent_offset, &parent)
+                        .map_err(BTreeError::IO);
+                }
+            }
+
+            let right = if index + 1 < parent_children.len() {
+                let right_offset = parent_children[index + 1].0;
+                let right = try!(self.read_node(right_offset));
+                if self.skipped.contains(&right_offset) { None }
+                else { Some((right_offset, right)) }
+            }
+            else {
+                None
+            };
+            if let Some((right_offset, mut right)) = right.clone() {
+                let mut right_entries = right.entries();
+                let mut right_children = right.links();
+                if right_entries.len() > self.k {
+                    // Rotate the smallest entry of the right sibling
+                    // up into the parent and the separator down into
+                    // this node.
+                    entries.push(parent_entries[index]);
+                    parent_entries[index] = right_entries.remove(0);
+                    if !right_children.is_empty() {
+                        let moved = right_children.remove(0);
+                        children.push(moved);
+                        try!(self.reparent(&[moved], offset));
+                    }
+                    right.set_contents(&right_entries, &right_children);
+                    try!(self.write_node(right_offset, &right)
+                         .map_err(BTreeError::IO));
+                    node.set_contents(&entries, &children);
+                    try!(self.write_node(offset, &node)
+                         .map_err(BTreeError::IO));
+                    parent_children[index + 1].1 = right.size();
+                    parent_children[index].1 = node.size();
+                    parent.set_contents(&parent_entries, &parent_children);
+                    return self.write_node(parent_offset, &parent)
+                        .map_err(BTreeError::IO);
+                }
+            }
+
+            // Neither sibling can spare an entry, so merge with one of
+            // them. Both have exactly k entries, so the merged node
+            // holds at most 2k.
+            match (left, right) {
+                (Some((left_offset, mut left)), _) => {
+                    let mut left_entries = left.entries();
+                    let mut left_children = left.links();
+                    left_entries.push(parent_entries.remove(index - 1));
+                    left_entries.extend(entries);
+                    try!(self.reparent(&children, left_offset));
+                    left_children.extend(children);
+                    parent_children.remove(index);
+                    left.set_contents(&left_entries, &left_children);
+                    try!(self.write_node(left_offset, &left)
+                         .map_err(BTreeError::IO));
+                    parent_children[index - 1].1 = left.size();
+                    self.free_node(offset);
+                },
+                (None, Some((right_offset, right))) => {
+                    entries.push(parent_entries.remove(index));
+                    entries.extend(right.entries());
+                    let right_children = right.links();
+                    try!(self.reparent(&right_children, offset));
+                    children.extend(right_children);
+                    parent_children.remove(index + 1);
+                    node.set_contents(&entries, &children);
+                    try!(self.write_node(offset, &node)
+                         .map_err(BTreeError::IO));
+                    parent_children[index].1 = node.size();
+                    self.free_node(right_offset);
+                },
+                // Both siblings are skipped, so leave this node short.
+                (None, None) if parent_children.len() > 1 => break,
+                (None, None) => return Err(BTreeError::IO(
+                    IOError::new(IOErrorKind::InvalidData,
+                                 "non-root node has no siblings"))),
+            }
+            offset = parent_offset;
+            node = parent;
+            entries = parent_entries;
+            children = parent_children;
+        }
+        node.set_contents(&entries, &children);
+        self.write_node(offset, &node).map_err(BTreeError::IO)
+    }
+
+    /// Return the slot at `offset` exactly as it is stored: the encoded
+    /// node padded to `node_length` bytes. Together with
+    /// `write_node_bytes()` on a replica, this ships changed nodes
+    /// without decoding and re-encoding them. Nothing checks that a
+    /// node starts at `offset`.
+    pub fn read_node_bytes(&mut self, offset: NodeOffset)
+                           -> Result<Vec<u8>, IOError> {
+        let mut bytes = vec![0; self.node_length as usize];
+        try!(self.get_slot(offset, &mut bytes[..]));
+        Ok(bytes)
+    }
+
+    /// Store a slot read from another tree with `read_node_bytes()` at
+    /// `offset`. The bytes must be exactly `node_length` long and
+    /// decode as a node that passes the checks `try_parse_node_at()`
+    /// makes. Writing the root's slot replaces the root.
+    ///
+    /// This is for a replica that only changes through these writes,
+    /// in the same slots as the tree it copies, which must have the
+    /// same `k`. The replica's free list is not updated, so mixing
+    /// this with `insert()` or `delete()` on the replica can put two
+    /// nodes in one slot.
+    pub fn write_node_bytes(&mut self, offset: NodeOffset, bytes: &[u8])
+                            -> Result<(), IOError> {
+        try!(self.check_writable().map_err(io_error));
+        if bytes.len() as u64 != self.node_length {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "bytes are not one node long"));
+        }
+        let node: BTreeNode = try!(deserialize(bytes)
+            .map_err(|_| IOError::new(IOErrorKind::InvalidData,
+                                      "failed to decode node")));
+        if !node.has_shape(self.k) || !node.keys_in_order() {
+            return Err(IOError::new(IOErrorKind::InvalidData,
+                                    "bytes are not a plausible node"));
+        }
+        // This is how a slot the corruption hook skipped is repaired.
+        self.skipped.remove(&offset);
+        try!(self.put_slot(offset, bytes.to_vec()));
+        if offset == self.root_offset() {
+            if self.cache_root {
+                self.root = node;
+            }
+            // The nodes below may not have arrived yet, so work the
+            // height out again when it is next asked for.
+            self.height = None;
+        }
+        Ok(())
+    }
+
+    /// Try to parse a node at an arbitrary offset in the file. This is
+    /// meant for recovery tools scanning a damaged file, so it never
+    /// panics on garbage: the bytes at `offset` are decoded and then
+    /// checked for the shape of a node in this tree (vectors sized for
+    /// `k`, entries and children packed to the left, keys in strictly
+    /// increasing order, one more child than keys for internal nodes).
+    ///
+    /// Nodes carry no checksum, so these structural checks are the
+    /// only verification possible. A node that passes is plausible,
+    /// not guaranteed to be live.
+    pub fn try_parse_node_at(&mut self, offset: NodeOffset)
+                             -> Result<NodeInfo, BTreeError> {
+        let node = try!(BTreeNode::load_node(self, offset));
+        if !node.keys_in_order() {
+            return Err(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidData,
+                             "data at offset is not a plausible node")));
+        }
+        let num_keys = node.num_keys();
+        let num_children = node.children.iter()
+            .take_while(|c| c.is_some()).count();
+        Ok(NodeInfo {
+            offset:       offset,
+            parent:       node.parent,
+            num_children: num_children,
+            keys:         node.data[..num_keys].iter()
+                .filter_map(|d| d.map(|(key, _)| key))
+                .collect(),
+        })
+    }
+
+    /// The number of keys in the tree less than `key`, whether or not
+    /// `key` itself is in the tree. With `select()` this makes the
+    /// tree an order-statistics structure.
+    ///
+    /// Each child pointer carries the number of entries under it, so
+    /// whole subtrees to the left of `key` are counted without being
+    /// read. This reads one node per level.
+    pub fn rank(&mut self, key: i64) -> Result<u64, BTreeError> {
+        let mut rank = 0;
+        let mut node = try!(self.root_node());
+        loop {
+            // The first `i` entries of the node are less than `key`,
+            // and so is everything under the children before them.
+            match node.find(key) {
+                Ok(i)  => {
+                    let below: u64 = node.counts[..i + 1].iter().sum();
+                    return Ok(rank + i as u64 + below);
+                },
+                Err(i) => {
+                    let below: u64 = node.counts[..i].iter().sum();
+                    rank += i as u64 + below;
+                    match node.child(i) {
+                        Some(child) => node = try!(self.read_node(child)),
+                        None        => return Ok(rank),
+                    }
+                },
+            }
+        }
+    }
+
+    /// The entry with `n` smaller keys in the tree, counting from 0, or
+    /// `None` if the tree has `n` or fewer entries. This is the
+    /// inverse of `rank()`, and lets the tree be paged through by
+    /// position.
+    ///
+    /// Like `rank()`, this uses the counts on child pointers to skip
+    /// whole subtrees, so it reads one node per level.
+    pub fn select(&mut self, n: u64) -> Result<BTreeData, BTreeError> {
+        let mut remaining = n;
+        let mut node = try!(self.root_node());
+        'descend: loop {
+            let num_keys = node.num_keys();
+            for i in 0..num_keys + 1 {
+                if let Some(child) = node.child(i) {
+                    if remaining < node.counts[i] {
+                        node = try!(self.read_node(child));
+                        continue 'descend;
+                    }
+                    remaining -= node.counts[i];
+                }
+                if i < num_keys {
+                    if remaining == 0 {
+                        return Ok(node.data[i]);
+                    }
+                    remaining -= 1;
+                }
+            }
+            return Ok(None);
+        }
+    }
+
+    /// Split the key space into `p` inclusive ranges `(low, high)`
+    /// holding roughly equal numbers of entries, for handing out to
+    /// workers. The ranges are in order and together cover every
+    /// `i64`, so keys inserted later still fall in exactly one.
+    ///
+    /// Each boundary is the key found by `select()` at an even step
+    /// through the entries, so this reads one node per level for
+    /// each of the `p - 1` boundaries. A tree with fewer than `p`
+    /// entries gives fewer ranges, and an empty tree gives one.
+    pub fn shards(&mut self, p: usize) -> Result<Vec<(i64, i64)>, BTreeError> {
+        if p == 0 {
+            return Err(BTreeError::IO(
+                IOError::new(IOErrorKind::InvalidInput,
+                             "p must be at least 1")));
+        }
+        let total = try!(self.root_node()).size();
+        let mut shards = vec![];
+        let mut low = i64::min_value();
+        for j in 1..p as u64 {
+            let step = j * total / p as u64;
+            if step == 0 {
+                continue;
+            }
+            if let Some((key, _)) = try!(self.select(step)) {
+                // Steps repeat when there are fewer entries than
+                // shards. Since `key > low`, `key - 1` can't overflow.
+                if key > low {
+                    shards.push((low, key - 1));
+                    low = key;
+                }
+            }
+        }
+        shards.push((low, i64::max_value()));
+        Ok(shards)
+    }
+
+    /// Get up to `n` of the smallest entries in the tree in ascending
+    /// order. The walk descends the left edge of the tree and moves
+    /// inward, stopping as soon as `n` entries have been collected,
+    /// so only the nodes holding those entries are read.
+    pub fn smallest_n(&mut self, n: usize)
+                      -> Result<Vec<(i64, u64)>, BTreeError> {
+        let mut entries = Vec::with_capacity(n);
+        let root = try!(self.root_node());
+        try!(self.collect_smallest(&root, n, &mut entries));
+        Ok(entries)
+    }
+
+    /// Get up to `n` of the largest entries in the tree, largest
+    /// first, so unlike `smallest_n()` the result is in descending
+    /// order. The walk is the mirror image of `smallest_n()`'s.
+    pub fn largest_n(&mut self, n: usize)
+                     -> Result<Vec<(i64, u64)>, BTreeError> {
+        let mut entries = Vec::with_capacity(n);
+        let root = try!(self.root_node());
+        try!(self.collect_largest(&root, n, &mut entries));
+        Ok(entries)
+    }
+
+    /// Get the entry for `key` together with up to `radius` entries
+    /// on either side of it, in ascending order. The neighbors are
+    /// gathered on the way back up from the single descent to `key`,
+    /// so only nodes adjacent to that path are read. An empty vector
+    /// is returned if `key` is not in the tree.
+    pub fn get_with_neighbors(&mut self, key: i64, radius: usize)
+                              -> Result<Vec<(i64, u64)>, BTreeError> {
+        let mut below = Vec::with_capacity(radius);
+        let mut above = Vec::with_capacity(radius);
+        let root = try!(self.root_node());
+        let found = try!(self.collect_around(&root, key, radius,
+                                             &mut below, &mut above));
+        match found {
+            Some(entry) => {
+                below.reverse();
+                below.push(entry);
+                below.extend(above);
+                Ok(below)
+            }
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn collect_around(&mut self, node: &BTreeNode, key: i64, radius: usize,
+                      below: &mut Vec<(i64, u64)>,
+                      above: &mut Vec<(i64, u64)>)
+                      -> Result<Option<(i64, u64)>, BTreeError> {
+        let (found, below_end, above_start) = match node.find(key) {
+            Ok(i) => {
+                // Everything in the children on either side of the
+                // key is a neighbor.
+                if let Some(offset) = node.child(i) {
+                    let child = try!(self.read_node(offset));
+                    try!(self.collect_largest(&child, radius, below));
+                }
+                if let Some(offset) = node.child(i + 1) {
+                    let child = try!(self.read_node(offset));
+                    try!(self.collect_smallest(&child, radius, above));
+                }
+                (node.data[i], i, i + 1)
+            }
+            Err(i) => match node.child(i) {
+                Some(offset) => {
+                    let child = try!(self.read_node(offset));
+                    let found = try!(self.collect_around(&child, key, radius,
+                                                         below, above));
+                    (found, i, i)
+                }
+                None => (None, i, i)
+            }
+        };
+        if found.is_none() {
+            return Ok(None);
+        }
+        for j in (0..below_end).rev() {
+            if below.len() >= radius {
+                break;
+            }
+            if let Some(entry) = node.data[j] {
+                below.push(entry);
+            }
+            if let Some(offset) = node.child(j) {
+                let child = try!(self.read_node(offset));
+                try!(self.collect_largest(&child, radius, below));
+            }
+        }
+        for j in above_start..node.num_keys() {
+            if above.len() >= radius {
+                break;
+            }
+            if let Some(entry) = node.data[j] {
+                above.push(entry);
+            }
+            if let Some(offset) = node.child(j + 1) {
+                let child = try!(self.read_node(offset));
+                try!(self.collect_smallest(&child, radius, above));
+            }
+        }
+        Ok(found)
+    }
+
+    fn collect_smallest(&mut self, node: &BTreeNode, n: usize,
+                        entries: &mut Vec<(i64, u64)>)
+                        -> Result<(), BTreeError> {
+        let num_keys = node.num_keys();
+        for i in 0..num_keys + 1 {
+            if entries.len() >= n {
+                break;
+            }
+            if let Some(offset) = node.child(i) {
+                let child = try!(self.read_node(offset));
+                try!(self.collect_smallest(&child, n, entries));
+            }
+            if i < num_keys && entries.len() < n {
+                if let Some(entry) = node.data[i] {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_largest(&mut self, node: &BTreeNode, n: usize,
+                       entries: &mut Vec<(i64, u64)>)
+                       -> Result<(), BTreeError> {
+        let num_keys = node.num_keys();
+        for i in (0..num_keys + 1).rev() {
+            if entries.len() >= n {
+                break;
+            }
+            if let Some(offset) = node.child(i) {
+                let child = try!(self.read_node(offset));
+                try!(self.collect_largest(&child, n, entries));
+            }
+            if i > 0 && entries.len() < n {
+                if let Some(entry) = node.data[i - 1] {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Drop for BTree<S> {
+    fn drop(&mut self) {
+        // There is no one left to report an error to. Trees that care
+        // call `flush()` themselves before they are dropped.
+        let _ = self.flush();
+    }
+}
+
+/// Iterator over the entries of a tree one leaf at a time, returned
+/// by `BTree::iter_by_leaf()`.
+pub struct LeafIter<'a, S: 'a + Storage = File> {
+    tree:  &'a mut BTree<S>,
+    stack: Vec<(BTreeNode, usize)>, // Ancestors and the child being visited
+    next:  Option<NodeOffset>,      // Subtree holding the next leaf
+}
+
+impl<'a, S: Storage> Iterator for LeafIter<'a, S> {
+    type Item = Result<Vec<(i64, u64)>, BTreeError>;
+
+    fn next(&mut self) -> Option<Result<Vec<(i64, u64)>, BTreeError>> {
+        let offset = match self.next.take() {
+            Some(offset) => offset,
+            None         => return None,
+        };
+        let mut node = match self.tree.read_node(offset) {
+            Ok(node) => node,
+            Err(err) => return Some(Err(err)),
+        };
+        while let Some(child) = node.child(0) {
+            let loaded = match self.tree.read_node(child) {
+                Ok(loaded) => loaded,
+                Err(err)   => return Some(Err(err)),
+            };
+            self.stack.push((node, 0));
+            node = loaded;
+        }
+        let mut group: Vec<(i64, u64)> = node.entries().into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect();
+        // The entry that follows a leaf, if any, is in the nearest
+        // ancestor that still has keys to the right of the path, and
+        // the next leaf is the leftmost one in the subtree after it.
+        while let Some((ancestor, i)) = self.stack.pop() {
+            if i < ancestor.num_keys() {
+                group.extend(ancestor.data[i]);
+                self.next = ancestor.child(i + 1);
+                self.stack.push((ancestor, i + 1));
+                break;
+            }
+        }
+        if group.is_empty() {
+            // Only an empty tree has an empty leaf.
+            return None;
+        }
+        Some(Ok(group))
+    }
+}
+
+/// A handle that can only read from a tree, returned by
+/// `BTree::read_guard()`. While it exists the tree is borrowed, so the
+/// borrow checker rejects any insert or delete and no split or merge
+/// can reshuffle nodes in the middle of a long scan.
+///
+/// This gives a consistent view by excluding writers rather than by
+/// taking a snapshot: nothing is copied, but nothing can be written
+/// until the guard is dropped. There is only ever one handle to a
+/// tree, so no lock is needed.
+pub struct ReadGuard<'a, S: 'a + Storage = File> {
+    tree: &'a mut BTree<S>,
+}
+
+impl<'a, S: Storage> ReadGuard<'a, S> {
+    /// See `BTree::lookup()`.
+    pub fn lookup(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        self.tree.lookup(key)
+    }
+
+    /// See `BTree::successor()`.
+    pub fn successor(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        self.tree.successor(key)
+    }
+
+    /// See `BTree::predecessor()`.
+    pub fn predecessor(&mut self, key: i64) -> Result<BTreeData, BTreeError> {
+        self.tree.predecessor(key)
+    }
+
+    /// See `BTree::any_in_range()`.
+    pub fn any_in_range(&mut self, low: i64, high: i64)
+                        -> Result<bool, BTreeError> {
+        self.tree.any_in_range(low, high)
+    }
+
+    /// See `BTree::leaf_free_slots()`.
+    pub fn leaf_free_slots(&mut self, key: i64) -> Result<usize, BTreeError> {
+        self.tree.leaf_free_slots(key)
+    }
+
+    /// See `BTree::rank()`.
+    pub fn rank(&mut self, key: i64) -> Result<u64, BTreeError> {
+        self.tree.rank(key)
+    }
+
+    /// See `BTree::select()`.
+    pub fn select(&mut self, n: u64) -> Result<BTreeData, BTreeError> {
+        self.tree.select(n)
+    }
+
+    /// See `BTree::shards()`.
+    pub fn shards(&mut self, p: usize) -> Result<Vec<(i64, i64)>, BTreeError> {
+        self.tree.shards(p)
+    }
+
+    /// See `BTree::height()`.
+    pub fn height(&mut self) -> Result<usize, BTreeError> {
+        self.tree.height()
+    }
+
+    /// See `BTree::smallest_n()`.
+    pub fn smallest_n(&mut self, n: usize)
+                      -> Result<Vec<(i64, u64)>, BTreeError> {
+        self.tree.smallest_n(n)
+    }
+
+    /// See `BTree::largest_n()`.
+    pub fn largest_n(&mut self, n: usize)
+                     -> Result<Vec<(i64, u64)>, BTreeError> {
+        self.tree.largest_n(n)
+    }
+
+    /// See `BTree::get_with_neighbors()`.
+    pub fn get_with_neighbors(&mut self, key: i64, radius: usize)
+                              -> Result<Vec<(i64, u64)>, BTreeError> {
+        self.tree.get_with_neighbors(key, radius)
+    }
+
+    /// See `BTree::collect_keys()`.
+    pub fn collect_keys(&mut self) -> Result<Vec<i64>, BTreeError> {
+        self.tree.collect_keys()
+    }
+
+    /// See `BTree::export_keys()`.
+    pub fn export_keys<W: Write>(&mut self, out: &mut W)
+                                 -> Result<(), BTreeError> {
+        self.tree.export_keys(out)
+    }
+
+    /// See `BTree::iter_by_leaf()`.
+    pub fn iter_by_leaf(&mut self) -> LeafIter<S> {
+        self.tree.iter_by_leaf()
+    }
+
+    /// See `BTree::with_node_keys()`.
+    pub fn with_node_keys<R, F>(&mut self, offset: NodeOffset, f: F)
+                                -> Result<R, BTreeError>
+        where F: FnOnce(&[i64]) -> R {
+        self.tree.with_node_keys(offset, f)
+    }
+
+    /// See `BTree::find_duplicates()`.
+    pub fn find_duplicates(&mut self) -> Result<Vec<i64>, BTreeError> {
+        self.tree.find_duplicates()
+    }
+
+    /// See `BTree::verify_counts()`.
+    pub fn verify_counts(&mut self) -> Result<Vec<NodeOffset>, BTreeError> {
+        self.tree.verify_counts()
+    }
+
+    /// See `BTree::to_dot()`.
+    pub fn to_dot<W: Write>(&mut self, out: &mut W) -> Result<(), IOError> {
+        self.tree.to_dot(out)
+    }
+
+    /// See `BTree::node_size_histogram()`.
+    pub fn node_size_histogram(&mut self, buckets: usize)
+                               -> Result<Vec<(u64, u64)>, IOError> {
+        self.tree.node_size_histogram(buckets)
+    }
+
+    /// See `BTree::read_node_bytes()`.
+    pub fn read_node_bytes(&mut self, offset: NodeOffset)
+                           -> Result<Vec<u8>, IOError> {
+        self.tree.read_node_bytes(offset)
+    }
+
+    /// See `BTree::try_parse_node_at()`.
+    pub fn try_parse_node_at(&mut self, offset: NodeOffset)
+                             -> Result<NodeInfo, BTreeError> {
+        self.tree.try_parse_node_at(offset)
+    }
+
+    /// See `BTree::compare_keys()`.
+    pub fn compare_keys(&self, a: &i64, b: &i64) -> Ordering {
+        self.tree.compare_keys(a, b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditOp, BTree, BTreeHeader, FreeList};
+    use offset::NodeOffset;
+    use storage::{Storage, MemoryStorage, MirrorStorage};
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::io::Error as IOError;
+    use std::io::ErrorKind as IOErrorKind;
+    use std::u64;
+
+    /// Memory that starts failing writes after a set number of them.
+    struct FailingStorage {
+        inner:     MemoryStorage,
+        puts_left: usize,
+    }
+
+    impl Storage for FailingStorage {
+        fn put(&mut self, data: &[u8], offset: NodeOffset)
+               -> Result<(), IOError> {
+            if self.puts_left == 0 {
+                return Err(IOError::new(IOErrorKind::Other, "injected"));
+            }
+            self.puts_left -= 1;
+            self.inner.put(data, offset)
+        }
+
+        fn get(&mut self, offset: NodeOffset, buffer: &mut [u8])
+               -> Result<(), IOError> {
+            self.inner.get(offset, buffer)
+        }
+
+        fn length(&mut self) -> Result<u64, IOError> {
+            self.inner.length()
+        }
+    }
+
+    /// The depth of every node below the root, and the number of
+    /// keys in it.
+    fn shape<S: Storage>(tree: &mut BTree<S>) -> Vec<(usize, usize)> {
+        let mut depths = HashMap::new();
+        let mut shape = vec![];
+        depths.insert(tree.root_offset(), 1);
+        tree.for_each_node(|offset, node| {
+            let depth = depths[&offset];
+            for child in node.offsets() {
+                depths.insert(child, depth + 1);
+            }
+            shape.push((depth, node.num_keys()));
+        }).unwrap();
+        shape
+    }
+
+    #[test]
+    fn free_list_matches_slot_size() {
+        let mut free_list = FreeList::new();
+        free_list.push(NodeOffset(16), 100);
+        free_list.push(NodeOffset(116), 60);
+        free_list.push(NodeOffset(176), 100);
+        assert_eq!(free_list.len(), 3);
+        // Nothing freed is big enough, so the caller appends.
+        assert_eq!(free_list.pop(140), None);
+        assert_eq!(free_list.pop(60), Some(NodeOffset(116)));
+        assert_eq!(free_list.pop(60), None);
+        assert_eq!(free_list.pop(100), Some(NodeOffset(176)));
+        assert_eq!(free_list.pop(100), Some(NodeOffset(16)));
+        assert_eq!(free_list.len(), 0);
+    }
+
+    #[test]
+    fn garbage_offsets_are_errors() {
+        let mut tree = BTree::empty(1).unwrap();
+        for offset in vec![3, 1 << 40, u64::MAX - 4] {
+            assert!(tree.try_parse_node_at(NodeOffset(offset)).is_err());
+        }
+    }
+
+    #[test]
+    fn verify_counts_reads_an_uncached_root() {
+        for &cache_root in &[true, false] {
+            let mut tree = BTree::empty(1).unwrap();
+            for key in 0..20 {
+                tree.insert(key, 0).unwrap();
+            }
+            tree.set_root_cache(cache_root).unwrap();
+            assert_eq!(tree.verify_counts().unwrap(), vec![]);
+            let root_offset = tree.root_offset();
+            let mut root = tree.root_node().unwrap();
+            root.counts[0] += 1;
+            tree.write_node(root_offset, &root).unwrap();
+            assert_eq!(tree.verify_counts().unwrap(), vec![root_offset]);
+        }
+    }
+
+    #[test]
+    fn rebalance_packs_nodes() {
+        let mut tree = BTree::empty(2).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, key as u64).unwrap();
+        }
+        for key in (0..2000).filter(|k| k % 2 == 0) {
+            tree.delete(key).unwrap();
+        }
+        tree.rebalance().unwrap();
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        for key in 0..2000 {
+            let expected = if key % 2 == 0 { None }
+                           else { Some((key, key as u64)) };
+            assert_eq!(tree.lookup(key).unwrap(), expected);
+        }
+        // 1000 keys take 201 leaves of up to 4 keys and 200 keys
+        // between them. Those take 41 nodes, then 9 and 2, under a
+        // root with one key.
+        let shape = shape(&mut tree);
+        assert_eq!(shape.len(), 201 + 41 + 9 + 2 + 1);
+        assert!(shape.iter().skip(1).all(|&(_, n)| n >= 2 && n <= 4));
+        let height = tree.height().unwrap();
+        assert_eq!(height, 5);
+        assert_eq!(shape.iter().filter(|&&(d, _)| d == height).count(), 201);
+        // The old nodes were freed, so inserting doesn't grow the file.
+        let length = tree.storage.length().unwrap();
+        tree.insert(0, 0).unwrap();
+        assert_eq!(tree.storage.length().unwrap(), length);
+    }
+
+    #[test]
+    fn failed_rebalance_keeps_the_old_tree() {
+        let storage = FailingStorage { inner: MemoryStorage::new(),
+                                       puts_left: usize::max_value() };
+        let mut tree = BTree::create(storage, 1).unwrap();
+        for key in 0..200 {
+            tree.insert(key, 1).unwrap();
+        }
+        tree.storage.puts_left = 20;
+        assert!(tree.rebalance().is_err());
+        tree.storage.puts_left = usize::max_value();
+        // What is in storage is still the old tree, not just what the
+        // handle has in memory.
+        let bytes = tree.storage.inner.as_bytes().to_vec();
+        let mut stored = BTree::open_slice(&bytes[..]).unwrap();
+        for key in 0..200 {
+            assert_eq!(stored.lookup(key).unwrap(), Some((key, 1)));
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, 1)));
+        }
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        tree.rebalance().unwrap();
+        for key in 0..200 {
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, 1)));
+        }
+    }
+
+    #[test]
+    fn mirrored_trees_open_from_either_copy() {
+        let names = ["mirrored_trees_primary.btree",
+                     "mirrored_trees_secondary.btree"];
+        {
+            let create = |name| fs::OpenOptions::new()
+                .read(true).write(true).create(true).truncate(true)
+                .open(name).unwrap();
+            let mut tree = BTree::create_in(
+                MirrorStorage::new(create(names[0]), create(names[1])), 2)
+                .unwrap();
+            for key in 0..100 {
+                tree.insert(key, key as u64 * 2).unwrap();
+            }
+            tree.delete(50).unwrap();
+        }
+        {
+            let open = |name| fs::OpenOptions::new()
+                .read(true).write(true).open(name).unwrap();
+            let mut tree = BTree::open_in(
+                MirrorStorage::new(open(names[0]), open(names[1]))).unwrap();
+            tree.insert(100, 200).unwrap();
+        }
+        for name in names.iter() {
+            let mut tree = BTree::open_read_only(name).unwrap();
+            for key in 0..101 {
+                let expected = if key == 50 { None }
+                               else { Some((key, key as u64 * 2)) };
+                assert_eq!(tree.lookup(key).unwrap(), expected);
+            }
+            assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        }
+        for name in names.iter() {
+            fs::remove_file(name).unwrap();
+        }
+    }
+
+    #[test]
+    fn expired_entries_are_absent_until_swept() {
+        let mut tree = BTree::empty(2).unwrap();
+        // Expiry times travel with their entries through splits, so
+        // insert enough of them to build a few levels.
+        for key in 0..300 {
+            match key % 3 {
+                0 => tree.insert_with_expiry(key, 1, 1000).unwrap(),
+                1 => tree.insert_with_expiry(key, 1, i64::max_value())
+                    .unwrap(),
+                _ => tree.insert(key, 1).unwrap(),
+            }
+        }
+        for key in 0..300 {
+            let expected = if key % 3 == 0 { None } else { Some((key, 1)) };
+            assert_eq!(tree.lookup(key).unwrap(), expected);
+        }
+        // Expired entries are still in the tree until they are swept.
+        assert_eq!(tree.rank(300).unwrap(), 300);
+        assert!(tree.insert(0, 2).is_err());
+
+        assert_eq!(tree.sweep_expired(999).unwrap(), 0);
+        assert_eq!(tree.sweep_expired(1000).unwrap(), 100);
+        assert_eq!(tree.sweep_expired(1000).unwrap(), 0);
+        assert_eq!(tree.rank(300).unwrap(), 200);
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        tree.insert(0, 2).unwrap();
+        assert_eq!(tree.lookup(0).unwrap(), Some((0, 2)));
+        for key in (1..300).filter(|k| k % 3 != 0) {
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, 1)));
+        }
+    }
+
+    #[test]
+    fn counts_survive_splits_rotations_and_merges() {
+        let mut tree = BTree::empty(1).unwrap();
+        // Inserting in a scrambled order splits leaves all over the
+        // tree; deleting in another one rotates and merges them.
+        for i in 0..101 {
+            tree.insert(i * 37 % 101, 0).unwrap();
+            assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        }
+        assert!(tree.height().unwrap() > 3);
+        for i in 0..100 {
+            let key = i * 53 % 101;
+            tree.delete(key).unwrap();
+            assert_eq!(tree.verify_counts().unwrap(), vec![]);
+            // The counts are what rank() and select() go by.
+            let remaining = 100 - i as u64;
+            assert_eq!(tree.rank(101).unwrap(), remaining);
+            assert!(tree.select(remaining - 1).unwrap().is_some());
+            assert_eq!(tree.select(remaining).unwrap(), None);
+        }
+        assert_eq!(tree.height().unwrap(), 1);
+        assert_eq!(tree.collect_keys().unwrap(), vec![100 * 53 % 101]);
+    }
+
+    #[test]
+    fn buffered_writes_wait_for_a_flush() {
+        use std::time::Duration;
+        let mut tree = BTree::empty(1).unwrap();
+        tree.buffer_writes(1000, Duration::from_secs(3600));
+        let length = tree.storage.length().unwrap();
+        let stored = tree.storage.as_bytes().to_vec();
+        for key in 0..50 {
+            tree.insert(key, key as u64).unwrap();
+        }
+        tree.delete(10).unwrap();
+        // Nothing has reached storage, but reads see the writes.
+        assert_eq!(tree.storage.as_bytes(), &stored[..]);
+        assert_eq!(tree.storage.length().unwrap(), length);
+        assert_eq!(tree.lookup(10).unwrap(), None);
+        assert_eq!(tree.lookup(20).unwrap(), Some((20, 20)));
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+
+        tree.flush().unwrap();
+        assert!(tree.storage.length().unwrap() > length);
+        // A small limit flushes as the tree goes, and a later
+        // buffer_writes() keeps what is already buffered.
+        tree.buffer_writes(4, Duration::from_secs(3600));
+        for key in 50..100 {
+            tree.insert(key, key as u64).unwrap();
+            assert!(tree.write_buffer.as_ref().unwrap().slots.len() < 4);
+        }
+        let pending = tree.write_buffer.as_ref().unwrap().slots.len();
+        tree.buffer_writes(1000, Duration::from_secs(3600));
+        assert_eq!(tree.write_buffer.as_ref().unwrap().slots.len(), pending);
+        tree.flush().unwrap();
+        let bytes = tree.storage.as_bytes().to_vec();
+        let mut stored = BTree::open_slice(&bytes[..]).unwrap();
+        for key in 0..100 {
+            let expected = if key == 10 { None }
+                           else { Some((key, key as u64)) };
+            assert_eq!(stored.lookup(key).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn idempotent_inserts_remember_a_window_of_ids() {
+        let name = "idempotent_inserts_remember_a_window_of_ids";
+        let ops = format!("{}.ops", name);
+        let _ = fs::remove_file(&ops);
+        let mut tree = BTree::empty(2).unwrap();
+        assert!(tree.insert_idempotent(1, 1, 1).is_err());
+        assert!(tree.track_op_ids(name, 0).is_err());
+        tree.track_op_ids(name, 3).unwrap();
+        assert_eq!(tree.insert_idempotent(10, 1, 1).unwrap(), true);
+        assert_eq!(tree.insert_idempotent(10, 1, 1).unwrap(), false);
+        assert_eq!(tree.insert_idempotent(11, 2, 1).unwrap(), true);
+        // A failed insert isn't recorded, so it can be retried.
+        assert!(tree.insert_idempotent(12, 2, 1).is_err());
+        assert_eq!(tree.insert_idempotent(12, 3, 1).unwrap(), true);
+
+        // The ids outlive the handle, and the window has to match.
+        let mut tree = BTree::empty(2).unwrap();
+        assert!(tree.track_op_ids(name, 4).is_err());
+        tree.track_op_ids(name, 3).unwrap();
+        assert_eq!(tree.insert_idempotent(11, 2, 1).unwrap(), false);
+        assert_eq!(tree.insert_idempotent(13, 4, 1).unwrap(), true);
+        // 13 pushed 10 out of the window, so it is applied again.
+        assert_eq!(tree.insert_idempotent(10, 1, 1).unwrap(), true);
+        assert_eq!(tree.insert_idempotent(12, 3, 1).unwrap(), false);
+        assert_eq!(tree.lookup(1).unwrap(), Some((1, 1)));
+        assert_eq!(tree.lookup(2).unwrap(), None);
+        fs::remove_file(&ops).unwrap();
+    }
+
+    #[test]
+    fn audit_log_records_successful_mutations() {
+        let name = "audit_log_records_successful_mutations.btree";
+        let audit = format!("{}.audit", name);
+        let _ = fs::remove_file(name);
+        let _ = fs::remove_file(&audit);
+        {
+            let mut tree = BTree::new_with_audit_log(name, 2).unwrap();
+            tree.insert(1, 1).unwrap();
+            tree.insert(2, 2).unwrap();
+            assert!(tree.insert(2, 3).is_err());
+            tree.delete(1).unwrap();
+            assert!(tree.delete(9).is_err());
+            assert!(tree.compare_and_swap(2, Some(2), 4).unwrap());
+            assert!(!tree.compare_and_swap(2, Some(2), 5).unwrap());
+        }
+        let mut tree = BTree::open_with_audit_log(name).unwrap();
+        tree.insert(3, 3).unwrap();
+        let records: Vec<(AuditOp, i64)> = tree.audit_log().unwrap()
+            .map(|record| (record.op, record.key))
+            .collect();
+        assert_eq!(records, vec![(AuditOp::Insert, 1),
+                                 (AuditOp::Insert, 2),
+                                 (AuditOp::Delete, 1),
+                                 (AuditOp::Update, 2),
+                                 (AuditOp::Insert, 3)]);
+        let timestamps: Vec<u64> = tree.audit_log().unwrap()
+            .map(|record| record.timestamp)
+            .collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+        // A tree without a log replays nothing.
+        assert_eq!(BTree::empty(1).unwrap().audit_log().unwrap().count(), 0);
+        fs::remove_file(name).unwrap();
+        fs::remove_file(&audit).unwrap();
+    }
+
+    #[test]
+    fn compare_and_swap_checks_the_current_value() {
+        let mut tree = BTree::empty(1).unwrap();
+        for key in 0..50 {
+            tree.insert(key * 2, 7).unwrap();
+        }
+        assert!(!tree.compare_and_swap(4, Some(8), 9).unwrap());
+        assert_eq!(tree.lookup(4).unwrap(), Some((4, 7)));
+        assert!(tree.compare_and_swap(4, Some(7), 9).unwrap());
+        assert_eq!(tree.lookup(4).unwrap(), Some((4, 9)));
+        // A present key doesn't match `None`, nor a missing one `Some`.
+        assert!(!tree.compare_and_swap(4, None, 10).unwrap());
+        assert!(!tree.compare_and_swap(5, Some(7), 10).unwrap());
+        assert_eq!(tree.lookup(5).unwrap(), None);
+        // Swapping in a missing key inserts it, splitting as needed.
+        for key in 0..50 {
+            assert!(tree.compare_and_swap(key * 2 + 1, None, 3).unwrap());
+        }
+        for key in 0..100 {
+            let value = if key == 4 { 9 } else if key % 2 == 0 { 7 } else { 3 };
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, value)));
+        }
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+
+        // An expired entry is missing as far as the swap can tell.
+        tree.insert_with_expiry(200, 7, 1000).unwrap();
+        tree.insert_with_expiry(201, 7, i64::max_value()).unwrap();
+        assert!(!tree.compare_and_swap(200, Some(7), 8).unwrap());
+        assert!(tree.compare_and_swap(200, None, 8).unwrap());
+        assert_eq!(tree.lookup(200).unwrap(), Some((200, 8)));
+        assert_eq!(tree.sweep_expired(i64::max_value() - 1).unwrap(), 0);
+        assert!(!tree.compare_and_swap(201, None, 8).unwrap());
+        assert!(tree.compare_and_swap(201, Some(7), 8).unwrap());
+        assert_eq!(tree.sweep_expired(i64::max_value()).unwrap(), 1);
+        assert_eq!(tree.lookup(201).unwrap(), None);
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn read_guards_see_what_the_tree_sees() {
+        use std::cmp::Ordering;
+        let mut tree = BTree::empty(1).unwrap();
+        for key in 0..20 {
+            tree.insert(key, 0).unwrap();
+        }
+        let root_offset = tree.root_offset();
+        let bytes = tree.read_node_bytes(root_offset).unwrap();
+        let histogram = tree.node_size_histogram(3).unwrap();
+        let mut dot = vec![];
+        tree.to_dot(&mut dot).unwrap();
+
+        let mut guard = tree.read_guard();
+        assert_eq!(guard.find_duplicates().unwrap(), vec![]);
+        assert_eq!(guard.verify_counts().unwrap(), vec![]);
+        let mut guard_dot = vec![];
+        guard.to_dot(&mut guard_dot).unwrap();
+        assert_eq!(guard_dot, dot);
+        assert_eq!(guard.node_size_histogram(3).unwrap(), histogram);
+        assert_eq!(guard.read_node_bytes(root_offset).unwrap(), bytes);
+        assert_eq!(guard.try_parse_node_at(root_offset).unwrap().offset,
+                   root_offset);
+        assert_eq!(guard.compare_keys(&1, &2), Ordering::Less);
+    }
+
+    #[test]
+    fn corruption_hook_sees_misshapen_nodes() {
+        use super::{BTreeNode, CorruptionAction};
+        use bincode::{serialize, Infinite};
+        let build = || {
+            let mut tree = BTree::empty(1).unwrap();
+            for key in 0..30 {
+                tree.insert(key * 10, 0).unwrap();
+            }
+            // A leaf that decodes fine but has too few data slots.
+            let mut leaf = None;
+            tree.for_each_node(|offset, node| if node.is_leaf() {
+                leaf = Some((offset, node.entries()[0].0));
+            }).unwrap();
+            let (offset, key) = leaf.unwrap();
+            let mut bad = BTreeNode::new(1, tree.root_offset());
+            bad.data.pop();
+            let mut bytes = serialize(&bad, Infinite).unwrap();
+            bytes.resize(tree.node_length as usize, 0);
+            tree.storage.put(&bytes[..], offset).unwrap();
+            (tree, offset, key, bytes)
+        };
+
+        let (mut tree, _, key, _) = build();
+        assert!(tree.lookup(key).is_err());
+        assert!(tree.leaf_free_slots(key).is_err());
+
+        let (mut tree, offset, key, bytes) = build();
+        tree.on_corruption = Some(Box::new(|_| CorruptionAction::Skip));
+        assert_eq!(tree.lookup(key).unwrap(), None);
+        let counts = tree.root_node().unwrap().counts;
+        assert!(tree.insert(key + 5, 0).is_err());
+        assert_eq!(&tree.read_node_bytes(offset).unwrap(), &bytes);
+        assert_eq!(tree.root_node().unwrap().counts, counts);
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        tree.rebuild_free_list().unwrap();
+        assert_eq!(tree.free_list.pop(tree.node_length), None);
+        // Deleting next to the skipped leaf can't merge with it.
+        for other in (0..key / 10).rev() {
+            let _ = tree.delete(other * 10);
+            assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        }
+        let empty = BTreeNode::new(1, tree.root_offset());
+        let mut repaired = serialize(&empty, Infinite).unwrap();
+        repaired.resize(tree.node_length as usize, 0);
+        tree.write_node_bytes(offset, &repaired).unwrap();
+        assert_eq!(tree.read_node_bytes(offset).unwrap(), repaired);
+
+        let (mut tree, offset, key, bytes) = build();
+        tree.on_corruption =
+            Some(Box::new(|_| CorruptionAction::SubstituteEmpty));
+        assert_eq!(tree.lookup(key).unwrap(), None);
+        tree.insert(key + 5, 0).unwrap();
+        assert_eq!(tree.lookup(key + 5).unwrap(), Some((key + 5, 0)));
+        assert!(tree.read_node_bytes(offset).unwrap() != bytes);
+        // The counts above the leaf no longer include what it held.
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn write_node_bytes_rejects_misshapen_nodes() {
+        use super::BTreeNode;
+        use bincode::{serialize, Infinite};
+        let mut tree = BTree::empty(1).unwrap();
+        for key in 0..10 {
+            tree.insert(key, 0).unwrap();
+        }
+        let root_offset = tree.root_offset();
+        let root = tree.read_node_bytes(root_offset).unwrap();
+        let encode = |node: &BTreeNode, length: u64| {
+            let mut bytes = serialize(node, Infinite).unwrap();
+            bytes.resize(length as usize, 0);
+            bytes
+        };
+        let mut short = BTreeNode::new(1, NodeOffset(0));
+        short.data.pop();
+        let mut unordered = BTreeNode::new(1, NodeOffset(0));
+        unordered.data[0] = Some((2, 0));
+        unordered.data[1] = Some((1, 0));
+        let mut childless = BTreeNode::new(1, NodeOffset(0));
+        childless.data[0] = Some((1, 0));
+        childless.children[0] = Some(root_offset);
+        for node in vec![short, unordered, childless] {
+            let bytes = encode(&node, tree.node_length);
+            let err = tree.write_node_bytes(root_offset, &bytes).unwrap_err();
+            assert_eq!(err.kind(), IOErrorKind::InvalidData);
+        }
+        assert_eq!(tree.read_node_bytes(root_offset).unwrap(), root);
+        for key in 0..10 {
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, 0)));
+        }
+    }
+
+    #[test]
+    fn estimate_size_saturates() {
+        use super::BTreeNode;
+        use std::usize;
+        for k in 1..6 {
+            let node_length = BTreeNode::max_length(k);
+            assert_eq!(BTree::estimate_size(k, 0), 16 + node_length);
+            assert_eq!(BTree::estimate_size(k, 2 * k as u64),
+                       16 + node_length);
+            assert_eq!(BTree::estimate_size(k, 2 * k as u64 + 1),
+                       16 + 2 * node_length);
+        }
+        assert_eq!(BTree::estimate_size(0, 10), BTree::estimate_size(1, 10));
+        assert_eq!(BTree::estimate_size(1, u64::MAX), u64::MAX);
+        assert_eq!(BTree::estimate_size(usize::MAX, 1), u64::MAX);
+    }
+
+    #[test]
+    fn failed_flushes_can_be_retried() {
+        use std::time::Duration;
+        let storage = FailingStorage { inner: MemoryStorage::new(),
+                                       puts_left: usize::max_value() };
+        let mut tree = BTree::create(storage, 1).unwrap();
+        tree.buffer_writes(1000, Duration::from_secs(3600));
+        for key in 0..50 {
+            tree.insert(key, key as u64).unwrap();
+        }
+        tree.storage.puts_left = 3;
+        assert!(tree.flush().is_err());
+        // What wasn't written is still buffered, and still read.
+        assert!(!tree.write_buffer.as_ref().unwrap().slots.is_empty());
+        for key in 0..50 {
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, key as u64)));
+        }
+        tree.storage.puts_left = usize::max_value();
+        tree.flush().unwrap();
+        assert!(tree.write_buffer.as_ref().unwrap().slots.is_empty());
+        let bytes = tree.storage.inner.as_bytes().to_vec();
+        let mut stored = BTree::open_slice(&bytes[..]).unwrap();
+        for key in 0..50 {
+            assert_eq!(stored.lookup(key).unwrap(), Some((key, key as u64)));
+        }
+        assert_eq!(stored.verify_counts().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn checked_length_matches_max_length() {
+        use super::BTreeNode;
+        use std::usize;
+        for k in 1..20 {
+            assert_eq!(BTreeNode::checked_length(k),
+                       Some(BTreeNode::max_length(k)));
+        }
+        assert_eq!(BTreeNode::checked_length(0), None);
+        assert_eq!(BTreeNode::checked_length(usize::MAX), None);
+    }
+
+    #[test]
+    fn with_node_keys_reads_what_read_node_reads() {
+        use super::{BTreeNode, CorruptionAction};
+        use bincode::{serialize, Infinite};
+        use std::time::Duration;
+        let mut tree = BTree::empty(2).unwrap();
+        tree.buffer_writes(1000, Duration::from_secs(3600));
+        for key in 0..200 {
+            tree.insert((key * 37) % 200, 0).unwrap();
+        }
+        tree.set_root_cache(false).unwrap();
+        let mut nodes = vec![];
+        tree.for_each_node(|offset, node| {
+            let keys: Vec<i64> = node.entries().iter()
+                .map(|&(key, _, _)| key).collect();
+            nodes.push((offset, keys));
+        }).unwrap();
+        // Some of the nodes are only in the write buffer so far.
+        for &(offset, ref keys) in &nodes {
+            let found = tree.with_node_keys(offset, |found| found.to_vec());
+            assert_eq!(&found.unwrap(), keys);
+        }
+        tree.flush().unwrap();
+
+        // A misshapen node goes to the corruption hook, as it would
+        // for any other read.
+        let (offset, _) = nodes[nodes.len() - 1];
+        let mut bad = BTreeNode::new(2, tree.root_offset());
+        bad.data.pop();
+        let mut bytes = serialize(&bad, Infinite).unwrap();
+        bytes.resize(tree.node_length as usize, 0);
+        tree.storage.put(&bytes[..], offset).unwrap();
+        assert!(tree.with_node_keys(offset, |keys| keys.len()).is_err());
+        tree.on_corruption = Some(Box::new(|_| CorruptionAction::Skip));
+        assert_eq!(tree.with_node_keys(offset, |keys| keys.len()).unwrap(),
+                   0);
+        assert!(tree.skipped.contains(&offset));
+    }
+
+    #[test]
+    fn replace_swaps_in_the_new_tree() {
+        let old_name = "replace_swaps_in_the_new_tree_old.btree";
+        let new_name = "replace_swaps_in_the_new_tree_new.btree";
+        let _ = fs::remove_file(old_name);
+        let _ = fs::remove_file(new_name);
+        BTree::new(old_name, 2).unwrap().insert(0, 1).unwrap();
+        {
+            let mut tree = BTree::new(new_name, 2).unwrap();
+            for key in 0..100 {
+                tree.insert(key, 2).unwrap();
+            }
+        }
+        BTree::replace(old_name, new_name).unwrap();
+        assert!(fs::metadata(new_name).is_err());
+        let mut tree = BTree::open(old_name).unwrap();
+        for key in 0..100 {
+            assert_eq!(tree.lookup(key).unwrap(), Some((key, 2)));
+        }
+        tree.insert(100, 2).unwrap();
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        fs::remove_file(old_name).unwrap();
+    }
+
+    #[test]
+    fn successor_and_predecessor_match_a_model() {
+        use std::collections::BTreeMap;
+        use std::collections::Bound::{Excluded, Unbounded};
+        let mut tree = BTree::empty(2).unwrap();
+        for &key in &[0, i64::min_value(), i64::max_value()] {
+            assert_eq!(tree.successor(key).unwrap(), None);
+            assert_eq!(tree.predecessor(key).unwrap(), None);
+        }
+        let mut model = BTreeMap::new();
+        for i in 0..200 {
+            let key = (i * 73) % 200 * 2;
+            tree.insert(key, i as u64).unwrap();
+            model.insert(key, i as u64);
+        }
+        // Every key is probed, present and absent, so the ones in
+        // internal nodes are too.
+        let mut internal = 0;
+        tree.for_each_node(|_, node| if !node.is_leaf() {
+            internal += node.num_keys();
+        }).unwrap();
+        assert!(internal > 0);
+        let probes = (-3..404).chain(vec![i64::min_value(),
+                                          i64::max_value()]);
+        for key in probes {
+            let successor = model.range((Excluded(key), Unbounded)).next()
+                .map(|(&k, &v)| (k, v));
+            let predecessor = model.range((Unbounded, Excluded(key)))
+                .next_back().map(|(&k, &v)| (k, v));
+            assert_eq!(tree.successor(key).unwrap(), successor);
+            assert_eq!(tree.predecessor(key).unwrap(), predecessor);
+        }
+    }
+
+    #[test]
+    fn shards_tile_the_key_space_evenly() {
+        let full = vec![(i64::min_value(), i64::max_value())];
+        let mut tree = BTree::empty(2).unwrap();
+        assert!(tree.shards(0).is_err());
+        assert_eq!(tree.shards(1).unwrap(), full);
+        assert_eq!(tree.shards(4).unwrap(), full);
+
+        let check = |shards: &[(i64, i64)], keys: &[i64], p: usize| {
+            assert_eq!(shards[0].0, i64::min_value());
+            assert_eq!(shards[shards.len() - 1].1, i64::max_value());
+            for pair in shards.windows(2) {
+                assert_eq!(pair[0].1 + 1, pair[1].0);
+            }
+            let even = keys.len() / p;
+            for &(low, high) in shards {
+                assert!(low <= high);
+                let count = keys.iter()
+                    .filter(|&&key| low <= key && key <= high).count();
+                assert!(count == even || count == even + 1,
+                        "{} entries in {:?}", count, (low, high));
+            }
+        };
+        for &key in &[-50, 10, 70] {
+            tree.insert(key, 0).unwrap();
+        }
+        // Fewer entries than shards: one entry in each.
+        let shards = tree.shards(10).unwrap();
+        assert_eq!(shards.len(), 3);
+        check(&shards, &[-50, 10, 70], 3);
+
+        let mut keys = vec![-50, 10, 70];
+        for i in 0..997 {
+            let key = (i * 389) % 997 * 3 - 1000;
+            if !keys.contains(&key) {
+                tree.insert(key, 0).unwrap();
+                keys.push(key);
+            }
+        }
+        assert_eq!(tree.shards(1).unwrap(), full);
+        for p in 2..12 {
+            let shards = tree.shards(p).unwrap();
+            assert_eq!(shards.len(), p);
+            check(&shards, &keys, p);
+        }
+    }
+
+    #[test]
+    fn smallest_and_largest_n_match_a_model() {
+        let mut tree = BTree::empty(2).unwrap();
+        assert_eq!(tree.smallest_n(5).unwrap(), vec![]);
+        assert_eq!(tree.largest_n(5).unwrap(), vec![]);
+        let mut model = vec![];
+        for i in 0..150 {
+            let key = (i * 61) % 150 - 75;
+            tree.insert(key, i as u64).unwrap();
+            model.push((key, i as u64));
+        }
+        model.sort();
+        let mut internal = 0;
+        tree.for_each_node(|_, node| if !node.is_leaf() {
+            internal += node.num_keys();
+        }).unwrap();
+        assert!(internal > 0);
+        // Every n from none to more than the tree holds, so the
+        // walks cross every leaf and every internal key.
+        for n in 0..160 {
+            let smallest: Vec<_> = model.iter().take(n).cloned().collect();
+            let largest: Vec<_> = model.iter().rev().take(n).cloned()
+                .collect();
+            assert_eq!(tree.smallest_n(n).unwrap(), smallest);
+            assert_eq!(tree.largest_n(n).unwrap(), largest);
+        }
+    }
+
+    #[test]
+    fn get_with_neighbors_matches_a_model() {
+        use std::cmp;
+        let mut tree = BTree::empty(2).unwrap();
+        assert_eq!(tree.get_with_neighbors(0, 3).unwrap(), vec![]);
+        let mut model = vec![];
+        for i in 0..120 {
+            let key = (i * 47) % 120 * 2;
+            tree.insert(key, i as u64).unwrap();
+            model.push((key, i as u64));
+        }
+        model.sort();
+        // Every key, so some sit in internal nodes, some at the edge
+        // of a leaf, and some at the ends of the tree.
+        for radius in vec![0, 1, 2, 3, 4, 7, 50, 500] {
+            for (i, &(key, _)) in model.iter().enumerate() {
+                let low = i.saturating_sub(radius);
+                let high = cmp::min(model.len(), i + radius + 1);
+                assert_eq!(tree.get_with_neighbors(key, radius).unwrap(),
+                           &model[low..high], "{} within {}", key, radius);
+                assert_eq!(tree.get_with_neighbors(key + 1, radius)
+                           .unwrap(), vec![]);
+            }
+        }
+    }
+
+    #[test]
+    fn find_duplicates_reports_a_planted_copy_once() {
+        use bincode::{serialize, Infinite};
+        let mut tree = BTree::empty(2).unwrap();
+        for key in 0..60 {
+            tree.insert(key * 10, 0).unwrap();
+        }
+        assert_eq!(tree.find_duplicates().unwrap(), vec![]);
+        // Give every entry of a leaf the leaf's first key, so the key
+        // appears three or more times in a row.
+        let mut leaf = None;
+        tree.for_each_node(|offset, node| {
+            if node.is_leaf() && node.num_keys() >= 3 && leaf.is_none() {
+                leaf = Some((offset, node.clone()));
+            }
+        }).unwrap();
+        let (offset, mut node) = leaf.unwrap();
+        let key = node.data[0].unwrap().0;
+        for i in 1..node.num_keys() {
+            node.data[i] = Some((key, 1));
+        }
+        let mut bytes = serialize(&node, Infinite).unwrap();
+        bytes.resize(tree.node_length as usize, 0);
+        tree.storage.put(&bytes[..], offset).unwrap();
+        assert_eq!(tree.find_duplicates().unwrap(), vec![key]);
+    }
+
+    #[test]
+    fn write_amplification_covers_a_window() {
+        let mut tree = BTree::empty(2).unwrap();
+        assert_eq!(tree.write_amplification(), 0.0);
+        for key in 0..100 {
+            tree.insert(key, 0).unwrap();
+        }
+        // Every insert writes at least a whole slot for 16 bytes.
+        let node_length = tree.node_length as f64;
+        assert!(tree.write_amplification() >= node_length / 16.0);
+        tree.reset_write_amplification();
+        assert_eq!(tree.write_amplification(), 0.0);
+        // Deletes write nodes but add no entries.
+        tree.delete(0).unwrap();
+        assert_eq!(tree.write_amplification(), 0.0);
+        tree.insert(0, 0).unwrap();
+        assert!(tree.write_amplification() > 1.0);
+    }
+
+    #[test]
+    fn persisted_trees_open_from_disk() {
+        use std::time::Duration;
+        let name = "persisted_trees_open_from_disk.btree";
+        let _ = fs::remove_file(name);
+        let mut tree = BTree::empty(2).unwrap();
+        // Whatever is still buffered goes out with the rest.
+        tree.buffer_writes(1000, Duration::from_secs(3600));
+        for key in 0..100 {
+            tree.insert(key, key as u64 + 1).unwrap();
+        }
+        tree.delete(50).unwrap();
+        assert!(!tree.write_buffer.as_ref().unwrap().slots.is_empty());
+        tree.persist_to(name).unwrap();
+        let err = tree.persist_to(name).unwrap_err();
+        assert_eq!(err.kind(), IOErrorKind::AlreadyExists);
+        drop(tree);
+        let mut tree = BTree::open(name).unwrap();
+        for key in 0..100 {
+            let expected = if key == 50 { None }
+                           else { Some((key, key as u64 + 1)) };
+            assert_eq!(tree.lookup(key).unwrap(), expected);
+        }
+        assert_eq!(tree.verify_counts().unwrap(), vec![]);
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn exported_keys_decode_to_the_collected_keys() {
+        use bincode::{deserialize_from, Infinite};
+        let mut tree = BTree::empty(2).unwrap();
+        let mut out = vec![];
+        tree.export_keys(&mut out).unwrap();
+        assert!(out.is_empty());
+        for i in 0..150 {
+            tree.insert((i * 53) % 150 - 75, 0).unwrap();
+        }
+        tree.export_keys(&mut out).unwrap();
+        let keys = tree.collect_keys().unwrap();
+        assert_eq!(keys.len(), 150);
+        let mut reader = &out[..];
+        let mut decoded = vec![];
+        while !reader.is_empty() {
+            let key: i64 = deserialize_from(&mut reader, Infinite).unwrap();
+            decoded.push(key);
+        }
+        assert_eq!(decoded, keys);
+    }
+
+    #[test]
+    fn leaf_batches_end_with_the_following_internal_key() {
+        let mut tree = BTree::empty(1).unwrap();
+        assert_eq!(tree.iter_by_leaf().count(), 0);
+        for key in 1..4 {
+            tree.insert(key, 0).unwrap();
+        }
+        // The root split into [1] and [3] around 2.
+        let batches: Vec<_> = tree.iter_by_leaf().map(|b| b.unwrap())
+            .collect();
+        assert_eq!(batches, vec![vec![(1, 0), (2, 0)], vec![(3, 0)]]);
+
+        for key in 4..60 {
+            tree.insert(key, 0).unwrap();
+        }
+        let mut leaves = vec![];
+        let mut internal = vec![];
+        tree.for_each_node(|_, node| {
+            let keys: Vec<i64> = node.entries().iter()
+                .map(|&(key, _, _)| key).collect();
+            if node.is_leaf() { leaves.push(keys) }
+            else { internal.extend(keys) }
+        }).unwrap();
+        leaves.sort();
+        let batches: Vec<Vec<i64>> = tree.iter_by_leaf()
+            .map(|b| b.unwrap().into_iter().map(|(key, _)| key).collect())
+            .collect();
+        assert_eq!(batches.len(), leaves.len());
+        // Each batch is a leaf, then the internal key after it, except
+        // for the last leaf, which has nothing after it.
+        for (i, batch) in batches.iter().enumerate() {
+            let leaf = &leaves[i];
+            assert_eq!(&batch[..leaf.len()], &leaf[..]);
+            if i + 1 < batches.len() {
+                assert_eq!(batch.len(), leaf.len() + 1);
+                assert!(internal.contains(&batch[leaf.len()]));
+            }
+            else {
+                assert_eq!(batch.len(), leaf.len());
+            }
+        }
+        let all: Vec<i64> = batches.into_iter().flat_map(|b| b).collect();
+        assert_eq!(all, (1..60).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn inspect_survives_a_garbage_header() {
+        use super::BTreeNode;
+        use bincode::{serialize, Infinite};
+        use std::usize;
+        let name = "inspect_survives_a_garbage_header.btree";
+        for header in vec![BTreeHeader(u64::MAX, 1),
+                           BTreeHeader(u64::MAX - 8, 1),
+                           BTreeHeader(16, 1 << 40),
+                           BTreeHeader(16, usize::MAX),
+                           BTreeHeader(16, 0)] {
+            let mut bytes = serialize(&header, Infinite).unwrap();
+            bytes.resize(64, 0);
+            File::create(name).unwrap().write_all(&bytes).unwrap();
+            let inspection = BTree::inspect(name).unwrap();
+            assert_eq!(inspection.node_length, header.0);
+            assert_eq!(inspection.expected_node_length,
+                       BTreeNode::checked_length(header.1));
+            assert!(!inspection.root_valid);
+        }
+        // A huge `k` still gets a size, without building the node.
+        assert!(BTreeNode::checked_length(1 << 40).is_some());
+        fs::remove_file(name).unwrap();
     }
 }
@@ -0,0 +1,24 @@
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+
+/// Count `sizes` in `buckets` equal-width buckets spanning the
+/// smallest to the largest of them. Returns the lowest size in each
+/// bucket and how many sizes fall into it, smallest first. This is
+/// the bucketing behind both trees' `node_size_histogram()`.
+pub fn bucket_sizes(sizes: Vec<u64>, buckets: usize)
+                    -> Result<Vec<(u64, u64)>, IOError> {
+    if buckets == 0 {
+        return Err(IOError::new(IOErrorKind::InvalidInput,
+                                "buckets must be at least 1"));
+    }
+    let smallest = sizes.iter().cloned().min().unwrap_or(0);
+    let largest = sizes.iter().cloned().max().unwrap_or(0);
+    let width = (largest - smallest) / buckets as u64 + 1;
+    let mut histogram: Vec<(u64, u64)> = (0..buckets as u64)
+        .map(|bucket| (smallest + bucket * width, 0))
+        .collect();
+    for size in sizes {
+        histogram[((size - smallest) / width) as usize].1 += 1;
+    }
+    Ok(histogram)
+}
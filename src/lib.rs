@@ -1,17 +1,141 @@
+//! File-backed B-Trees.
+//!
+//! There are two implementations, selected with cargo features:
+//!
+//! * `fixed` (the default) enables `btree1`. Nodes have a fixed size
+//!   and are rewritten in place. Keys are `i64` and values are `u64`.
+//! * `append` enables `btree`. Nodes are generic over their key and
+//!   value types. The file is append-only: every update writes new
+//!   copies of the nodes on the path to the root, then a footer that
+//!   locates the new root.
+//!
+//! `BTree` at the crate root is the fixed-size tree whenever the
+//! `fixed` feature is enabled. It is the append-only tree when only
+//! `append` is enabled.
+
 #[macro_use]
 extern crate serde_derive;
 extern crate bincode;
 extern crate serde;
 
+mod histogram;
+mod offset;
+pub mod storage;
+
+#[cfg(feature = "append")]
 pub mod btree;
+#[cfg(feature = "fixed")]
+pub mod btree1;
+
+pub use offset::NodeOffset;
+#[cfg(feature = "fixed")]
+pub use btree1::{BTree, BTreeError};
+#[cfg(all(feature = "append", not(feature = "fixed")))]
+pub use btree::BTree;
+
+#[cfg(not(any(feature = "fixed", feature = "append")))]
+compile_error!("enable at least one of the `fixed` or `append` features");
 
-#[cfg(test)]
+#[cfg(all(test, feature = "fixed"))]
 mod tests {
-    use btree::{BTree};
-    use std::error::Error;
+    use BTree;
+    use BTreeError;
     use std::fs;
-    
+    use std::io::ErrorKind;
+
     #[test]
     fn it_works() {
+        let name = "it_works.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree = BTree::new(name, 2).unwrap();
+            for key in 0..100 {
+                tree.insert((key * 37) % 100, key as u64).unwrap();
+            }
+            for key in 0..100 {
+                let value = tree.lookup((key * 37) % 100).unwrap();
+                assert_eq!(value, Some(((key * 37) % 100, key as u64)));
+            }
+            match tree.insert(5, 0) {
+                Err(BTreeError::Exists) => (),
+                _                       => panic!("duplicate insert"),
+            }
+            for key in (0..100).filter(|k| k % 3 == 0) {
+                tree.delete(key).unwrap();
+            }
+        }
+        let mut tree = BTree::open(name).unwrap();
+        for key in 0..100 {
+            let expected = if key % 3 == 0 { None }
+                           else { Some((key, ((key * 73) % 100) as u64)) };
+            assert_eq!(tree.lookup(key).unwrap(), expected);
+        }
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let name = "read_only_rejects_writes.btree";
+        let _ = fs::remove_file(name);
+        {
+            let mut tree = BTree::new(name, 2).unwrap();
+            tree.insert(1, 10).unwrap();
+        }
+        let mut tree = BTree::open_read_only(name).unwrap();
+        assert_eq!(tree.lookup(1).unwrap(), Some((1, 10)));
+        for result in vec![tree.insert(2, 20), tree.delete(1)] {
+            match result {
+                Err(BTreeError::IO(ref err))
+                    if err.kind() == ErrorKind::PermissionDenied => (),
+                other => panic!("write was not rejected: {:?}", other),
+            }
+        }
+        assert_eq!(tree.lookup(1).unwrap(), Some((1, 10)));
+        assert_eq!(tree.lookup(2).unwrap(), None);
+        fs::remove_file(name).unwrap();
+    }
+
+    #[test]
+    fn empty_tree_lives_in_memory() {
+        let mut tree = BTree::empty(1).unwrap();
+        for key in 0..50 {
+            tree.insert(key, key as u64 * 2).unwrap();
+        }
+        for key in (0..50).filter(|k| k % 2 == 0) {
+            tree.delete(key).unwrap();
+        }
+        for key in 0..50 {
+            let expected = if key % 2 == 0 { None }
+                           else { Some((key, key as u64 * 2)) };
+            assert_eq!(tree.lookup(key).unwrap(), expected);
+        }
+        assert!(BTree::empty(0).is_err());
+    }
+
+    #[test]
+    fn ranges_reach_the_extreme_keys() {
+        use std::i64;
+        let mut tree = BTree::empty(1).unwrap();
+        assert!(!tree.any_in_range(i64::MIN, i64::MAX).unwrap());
+        for key in (-20..20).map(|k| k * 1000) {
+            tree.insert(key, 0).unwrap();
+        }
+        assert!(!tree.any_in_range(i64::MIN, i64::MIN).unwrap());
+        assert!(!tree.any_in_range(i64::MAX, i64::MAX).unwrap());
+        assert!(!tree.any_in_range(i64::MIN, -20001).unwrap());
+        assert!(!tree.any_in_range(19001, i64::MAX).unwrap());
+        tree.insert(i64::MIN, 1).unwrap();
+        tree.insert(i64::MAX, 2).unwrap();
+        assert!(tree.any_in_range(i64::MIN, i64::MAX).unwrap());
+        assert!(tree.any_in_range(i64::MIN, i64::MIN).unwrap());
+        assert!(tree.any_in_range(i64::MAX, i64::MAX).unwrap());
+        assert!(!tree.any_in_range(i64::MAX, i64::MIN).unwrap());
+        assert!(!tree.any_in_range(i64::MIN + 1, -20001).unwrap());
+        assert_eq!(tree.smallest_n(1).unwrap(), vec![(i64::MIN, 1)]);
+        assert_eq!(tree.largest_n(1).unwrap(), vec![(i64::MAX, 2)]);
+        assert_eq!(tree.get_with_neighbors(i64::MAX, 1).unwrap(),
+                   vec![(19000, 0), (i64::MAX, 2)]);
+        assert_eq!(tree.rank(i64::MAX).unwrap(), 41);
+        assert_eq!(tree.select(0).unwrap(), Some((i64::MIN, 1)));
     }
 }
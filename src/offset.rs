@@ -0,0 +1,10 @@
+/// The location of a node in storage.
+///
+/// Node addresses and the values stored in a tree are both `u64`s on
+/// disk. Wrapping addresses in their own type means the compiler
+/// rejects code that follows a value as if it were a child pointer,
+/// or stores a child pointer as a value. It serializes exactly like
+/// the `u64` it wraps, so the file format is unchanged.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug,
+         PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeOffset(pub u64);
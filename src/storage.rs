@@ -0,0 +1,293 @@
+use std::io::Error as IOError;
+use std::io::ErrorKind as IOErrorKind;
+use std::io::{Seek, SeekFrom, Read, Write};
+use std::fs::File;
+
+use offset::NodeOffset;
+
+/// The Storage trait provides functions needed to put and get btrees
+/// from some (possibly persistent) storage medium.
+pub trait Storage {
+    /// Put data into storage at the given offset.
+    fn put(&mut self, data: &[u8], offset: NodeOffset) -> Result<(), IOError>;
+
+    /// Append data to the storage location returning the offset
+    /// where the write began.
+    fn append(&mut self, data: &[u8]) -> Result<NodeOffset, IOError> {
+        Err(IOError::new(IOErrorKind::Other, "not implemented"))
+    }
+
+    /// Get data from storage at the given offset. Attempts to fill
+    /// the entire slice refered to by buffer, if it cannot (ie. not
+    /// enough data) then the result will be an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - the "address" to begin reading at
+    ///
+    /// * `buffer` - the buffer to fill with the data. This should
+    ///              work like the buffer parameter to
+    ///              `Read::read_exact()`
+    fn get(&mut self, offset: NodeOffset, buffer: &mut [u8]) -> Result<(), IOError>;
+
+    /// The number of bytes in storage, which is the offset the next
+    /// `append()` would write at.
+    fn length(&mut self) -> Result<u64, IOError>;
+}
+
+impl Storage for File {
+    fn put(&mut self, data: &[u8], offset: NodeOffset) -> Result<(), IOError> {
+        self.seek(SeekFrom::Start(offset.0)).map_err(|err| err)
+            .and_then(|at| {
+                if at != offset.0 {
+                    Err(IOError::new(IOErrorKind::Other, "failed to seek"))
+                }
+                else {
+                    self.write_all(data)
+                }
+            })
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<NodeOffset, IOError> {
+        self.seek(SeekFrom::End(0)).map_err(|err| err)
+            .and_then(|at| {
+                match self.write_all(data) {
+                    Ok(_)    => Ok(NodeOffset(at)),
+                    Err(err) => Err(err),
+                }
+            })
+    }
+
+    fn get(&mut self, offset: NodeOffset, buffer: &mut [u8]) -> Result<(), IOError> {
+        self.seek(SeekFrom::Start(offset.0)).map_err(|err| err)
+            .and_then(|at| {
+                if at != offset.0 {
+                    Err(IOError::new(IOErrorKind::Other, "failed to seek"))
+                }
+                else {
+                    self.read_exact(buffer)
+                }
+            })
+    }
+
+    fn length(&mut self) -> Result<u64, IOError> {
+        self.seek(SeekFrom::End(0))
+    }
+}
+
+
+/// The `length` bytes at `offset` as a range of indices into
+/// `available` bytes. Fails rather than overflowing or running off
+/// the end when `offset` is garbage.
+fn span(offset: NodeOffset, length: usize, available: usize)
+        -> Result<(usize, usize), IOError> {
+    match offset.0.checked_add(length as u64) {
+        Some(end) if end <= available as u64 =>
+            Ok((offset.0 as usize, end as usize)),
+        _ => Err(IOError::new(IOErrorKind::UnexpectedEof,
+                              "read past the end of storage")),
+    }
+}
+
+/// Storage held in a `Vec<u8>`, for trees that only need to live as
+/// long as the process. Nothing is ever written to disk.
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage { data: vec![] }
+    }
+
+    /// The bytes held in this storage, laid out exactly as they would
+    /// be in a file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..]
+    }
+}
+
+impl Storage for MemoryStorage {
+    /// Write `data` at `offset`, growing the storage if it runs past
+    /// the end. A write can't start past the end, since that would
+    /// leave a gap of bytes nobody wrote.
+    fn put(&mut self, data: &[u8], offset: NodeOffset) -> Result<(), IOError> {
+        if offset.0 > self.data.len() as u64 {
+            return Err(IOError::new(IOErrorKind::InvalidInput,
+                                    "write starts past the end of storage"));
+        }
+        let start = offset.0 as usize;
+        let end = start + data.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<NodeOffset, IOError> {
+        let offset = NodeOffset(self.data.len() as u64);
+        self.data.extend_from_slice(data);
+        Ok(offset)
+    }
+
+    fn get(&mut self, offset: NodeOffset, buffer: &mut [u8]) -> Result<(), IOError> {
+        let (start, end) = try!(span(offset, buffer.len(), self.data.len()));
+        buffer.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+
+    fn length(&mut self) -> Result<u64, IOError> {
+        Ok(self.data.len() as u64)
+    }
+}
+
+/// Storage over borrowed bytes, which can be read but never
+/// written.
+impl<'a> Storage for &'a [u8] {
+    fn put(&mut self, _data: &[u8], _offset: NodeOffset)
+           -> Result<(), IOError> {
+        Err(IOError::new(IOErrorKind::PermissionDenied,
+                         "storage is read only"))
+    }
+
+    fn append(&mut self, _data: &[u8]) -> Result<NodeOffset, IOError> {
+        Err(IOError::new(IOErrorKind::PermissionDenied,
+                         "storage is read only"))
+    }
+
+    fn get(&mut self, offset: NodeOffset, buffer: &mut [u8]) -> Result<(), IOError> {
+        let (start, end) = try!(span(offset, buffer.len(), self.len()));
+        buffer.copy_from_slice(&self[start..end]);
+        Ok(())
+    }
+
+    fn length(&mut self) -> Result<u64, IOError> {
+        Ok(self.len() as u64)
+    }
+}
+
+/// Storage that writes everything to two backends and reads from the
+/// first, falling back to the second when a read from the first
+/// fails. The secondary is a copy kept in step write by write, so the
+/// tree survives losing or damaging either one.
+///
+/// This is redundancy for a single writer, not consistency. The two
+/// writes aren't atomic: a crash or error between them leaves the
+/// backends differing by that one write, and nothing coordinates
+/// several writers.
+pub struct MirrorStorage<A: Storage, B: Storage> {
+    primary:   A,
+    secondary: B,
+}
+
+impl<A: Storage, B: Storage> MirrorStorage<A, B> {
+    /// Mirror writes to `primary` and `secondary`. They should start
+    /// out holding the same bytes.
+    pub fn new(primary: A, secondary: B) -> MirrorStorage<A, B> {
+        MirrorStorage { primary: primary, secondary: secondary }
+    }
+}
+
+impl<A: Storage, B: Storage> Storage for MirrorStorage<A, B> {
+    fn put(&mut self, data: &[u8], offset: NodeOffset) -> Result<(), IOError> {
+        try!(self.primary.put(data, offset));
+        self.secondary.put(data, offset)
+    }
+
+    /// Append to both backends. Fails if they put the data at
+    /// different offsets, which means they had already diverged.
+    fn append(&mut self, data: &[u8]) -> Result<NodeOffset, IOError> {
+        let offset = try!(self.primary.append(data));
+        if try!(self.secondary.append(data)) != offset {
+            return Err(IOError::new(IOErrorKind::Other,
+                                    "mirrored storage has diverged"));
+        }
+        Ok(offset)
+    }
+
+    fn get(&mut self, offset: NodeOffset, buffer: &mut [u8]) -> Result<(), IOError> {
+        match self.primary.get(offset, buffer) {
+            Ok(())   => Ok(()),
+            Err(_)   => self.secondary.get(offset, buffer),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, IOError> {
+        self.primary.length()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Storage, MemoryStorage, MirrorStorage};
+    use offset::NodeOffset;
+    use std::io::ErrorKind;
+    use std::u64;
+
+    #[test]
+    fn memory_storage_rejects_out_of_range_offsets() {
+        let mut storage = MemoryStorage::new();
+        storage.put(&[1, 2, 3, 4], NodeOffset(0)).unwrap();
+        storage.put(&[5, 6], NodeOffset(4)).unwrap();
+        let mut buffer = [0; 4];
+        for offset in vec![3, 6, u64::MAX - 2] {
+            let err = storage.get(NodeOffset(offset), &mut buffer[..])
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+        for offset in vec![7, u64::MAX - 1] {
+            let err = storage.put(&[0], NodeOffset(offset)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(storage.length().unwrap(), 6);
+        storage.get(NodeOffset(2), &mut buffer[..]).unwrap();
+        assert_eq!(buffer, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn slice_storage_rejects_out_of_range_offsets() {
+        let bytes = [1, 2, 3, 4, 5, 6];
+        let mut storage = &bytes[..];
+        let mut buffer = [0; 4];
+        for offset in vec![3, 6, u64::MAX - 2] {
+            let err = Storage::get(&mut storage, NodeOffset(offset),
+                                   &mut buffer[..])
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+        let err = Storage::put(&mut storage, &[0], NodeOffset(0)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        Storage::get(&mut storage, NodeOffset(2), &mut buffer[..]).unwrap();
+        assert_eq!(buffer, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn mirror_storage_reads_the_secondary_when_the_primary_fails() {
+        let mut primary = MemoryStorage::new();
+        primary.put(&[9, 9], NodeOffset(0)).unwrap();
+        let mut secondary = MemoryStorage::new();
+        secondary.put(&[1, 2, 3, 4, 5, 6], NodeOffset(0)).unwrap();
+        let mut storage = MirrorStorage::new(primary, secondary);
+        let mut buffer = [0; 2];
+        // The primary is read whenever it can answer.
+        storage.get(NodeOffset(0), &mut buffer[..]).unwrap();
+        assert_eq!(buffer, [9, 9]);
+        // Past its end it fails, and the secondary answers instead.
+        storage.get(NodeOffset(4), &mut buffer[..]).unwrap();
+        assert_eq!(buffer, [5, 6]);
+        let err = storage.get(NodeOffset(5), &mut buffer[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn mirror_storage_appends_fail_once_the_copies_diverge() {
+        let mut storage = MirrorStorage::new(MemoryStorage::new(),
+                                             MemoryStorage::new());
+        assert_eq!(storage.append(&[1, 2]).unwrap(), NodeOffset(0));
+        assert_eq!(storage.append(&[3]).unwrap(), NodeOffset(2));
+        storage.secondary.append(&[0]).unwrap();
+        let err = storage.append(&[4]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+}